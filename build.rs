@@ -14,6 +14,12 @@ use std::io::Write;
 use std::path::PathBuf;
 
 fn main() {
+    // Only the RP2040 firmware needs the memory layout, don't leak it into
+    // the link of crates using the driver as a library.
+    if env::var_os("CARGO_FEATURE_RP2040").is_none() {
+        return;
+    }
+
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
@@ -1,132 +1,2020 @@
-use embedded_hal::i2c::I2c;
+use core::convert::Infallible;
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::{ErrorType, OutputPin};
+use embedded_hal::i2c::{I2c, Operation};
+#[cfg(feature = "async")]
+use embedded_hal_async::digital::Wait;
 
 pub mod regs;
+pub mod scale;
 use regs::*;
+use scale::*;
 
+/// Default I2C address of the AP33772.
 const ADDR: u8 = 0x51;
 
-pub struct AP33772<I2C> {
+/// Delay before the first retry of a failed transaction, doubled for every
+/// further attempt.
+const RETRY_BACKOFF_MS: u32 = 1;
+
+/// Upper limit of the delay between retries.
+const RETRY_BACKOFF_MAX_MS: u32 = 1000;
+
+/// Default time after which `tick()` re-issues a request that has not
+/// led to a contract.
+const NEGOTIATION_TIMEOUT_MS: u32 = 1000;
+
+/// Interval for polling the status while waiting for the negotiation.
+const POLL_INTERVAL_MS: u32 = 10;
+
+/// Time for the source to settle after each request of
+/// `request_pps_regulated()`.
+const PPS_SETTLE_MS: u32 = 50;
+
+/// Maximum number of requests of `request_pps_regulated()`.
+const PPS_REGULATION_STEPS: u8 = 8;
+
+/// Maximum offset (mV) of the requested voltage from the target in
+/// `request_pps_regulated()`, so that a bogus measurement cannot drive the
+/// output far off.
+const PPS_REGULATION_MAX_MV: u16 = 500;
+
+#[derive(Debug)]
+pub enum Error<E> {
+    I2c(E),
+    InvalidPdoIndex,
+    VoltageOutOfRange,
+    CurrentOutOfRange,
+    NoCompatiblePdo,
+    /// the source did not accept the requested PDO
+    NegotiationRejected,
+    /// the device does not behave like an AP33772, see `probe()`
+    UnexpectedDevice,
+    /// a transfer returned fewer valid bytes than expected, e.g. an empty
+    /// PDO slot within the reported count
+    ShortRead,
+    /// fewer plausible PDOs were read than `read_npdos()` reports
+    PdoCountMismatch,
+    /// a value does not fit the register, e.g. an OCP threshold above
+    /// 12750 mA or an NTC table that is not decreasing
+    ValueOutOfRange,
+    /// the interrupt pin could not be read, see `wait_event()`
+    Interrupt,
+    /// the temperature was read before the NTC table was written with
+    /// `write_tr()`
+    NtcNotConfigured,
+}
+
+impl<E> From<ProfileError> for Error<E> {
+    fn from(err: ProfileError) -> Self {
+        match err {
+            ProfileError::VoltageOutOfRange => Error::VoltageOutOfRange,
+            ProfileError::CurrentOutOfRange => Error::CurrentOutOfRange,
+        }
+    }
+}
+
+/// Voltage (mV) and current (mA) requirements of the sink.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PowerRequest {
+    pub v_nom: u16,
+    pub v_min: u16,
+    pub v_max: u16,
+    pub i_nom: u16,
+    pub i_min: u16,
+}
+
+/// Rule for choosing among the PDOs compatible with a `PowerRequest`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SelectionPolicy {
+    /// Programmable beats fixed, then more current.
+    #[default]
+    PreferProgrammable,
+    /// Highest current, regardless of type.
+    MaxCurrent,
+    /// Highest `max_power_mw()`.
+    MaxPower,
+    /// Nominal voltage closest to `v_nom`.
+    ClosestVoltage,
+}
+
+impl PDO {
+    /// Whether `policy` strictly prefers this PDO over `other`, `v_nom` being
+    /// the nominal voltage (mV) for `SelectionPolicy::ClosestVoltage`. Ties
+    /// are not preferred, so the first of equal PDOs is kept.
+    pub fn better_than(&self, other: &PDO, policy: SelectionPolicy, v_nom: u32) -> bool {
+        match policy {
+            SelectionPolicy::PreferProgrammable => match (self, other) {
+                (PDO::Programmable(_), PDO::Fixed(_)) => true,
+                (PDO::Fixed(_), PDO::Programmable(_)) => false,
+                _ => self.imax() > other.imax(),
+            },
+            SelectionPolicy::MaxCurrent => self.imax() > other.imax(),
+            SelectionPolicy::MaxPower => self.max_power_mw() > other.max_power_mw(),
+            SelectionPolicy::ClosestVoltage => {
+                let dist = |pdo: &PDO| v_nom.clamp(pdo.vmin(), pdo.vmax()).abs_diff(v_nom);
+                dist(self) < dist(other)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FaultKind {
+    Ovp,
+    Ocp,
+    Otp,
+    Derating,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PdEvent {
+    Fault(FaultKind),
+    NewPdos,
+    Negotiated,
+    Ready,
+    Nothing,
+    /// An RDO for the PDO at the given position was written
+    Requested(PdoPosition),
+    /// The source did not accept the last request
+    Rejected,
+    /// The link was lost while a request was pending, e.g. a hard reset by
+    /// the source after an invalid RDO, see `update()`
+    SourceReset,
+}
+
+impl PdEvent {
+    /// Lowest `Verbosity` at which the event is reported by `Filtered`.
+    pub fn verbosity(&self) -> Verbosity {
+        match self {
+            PdEvent::Fault(_) | PdEvent::Negotiated | PdEvent::Rejected | PdEvent::SourceReset => {
+                Verbosity::Summary
+            }
+            PdEvent::NewPdos | PdEvent::Ready | PdEvent::Nothing | PdEvent::Requested(_) => {
+                Verbosity::Verbose
+            }
+        }
+    }
+
+    /// Decode the most relevant event from a status register value, faults
+    /// taking precedence over everything else.
+    pub fn from_status(status: &Status) -> Self {
+        if status.ovp() {
+            PdEvent::Fault(FaultKind::Ovp)
+        } else if status.ocp() {
+            PdEvent::Fault(FaultKind::Ocp)
+        } else if status.otp() {
+            PdEvent::Fault(FaultKind::Otp)
+        } else if status.derating() {
+            PdEvent::Fault(FaultKind::Derating)
+        } else if !status.ready() {
+            PdEvent::Nothing
+        } else if status.newpdos() {
+            PdEvent::NewPdos
+        } else if status.success() {
+            PdEvent::Negotiated
+        } else {
+            PdEvent::Ready
+        }
+    }
+}
+
+/// Snapshot of the measurements and the status, see `measure_all()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Telemetry {
+    pub voltage_mv: u16,
+    pub current_ma: u16,
+    /// °C, `None` before the NTC table was written, see `read_temp()`
+    pub temp: Option<u8>,
+    pub status: Status,
+}
+
+/// First byte of a frame from `Telemetry::to_frame()`.
+pub const TELEMETRY_FRAME_MAGIC: u8 = 0xa5;
+
+impl Telemetry {
+    /// Pack into a fixed 8-byte frame for binary logging:
+    ///
+    /// | byte | content                              |
+    /// |------|--------------------------------------|
+    /// | 0    | `TELEMETRY_FRAME_MAGIC`              |
+    /// | 1    | `seq`, e.g. to detect dropped frames |
+    /// | 2..4 | voltage in mV, little-endian         |
+    /// | 4..6 | current in mA, little-endian         |
+    /// | 6    | temperature in °C, 0xff if unknown   |
+    /// | 7    | status register                      |
+    pub fn to_frame(&self, seq: u8) -> [u8; 8] {
+        let [v0, v1] = self.voltage_mv.to_le_bytes();
+        let [i0, i1] = self.current_ma.to_le_bytes();
+        [
+            TELEMETRY_FRAME_MAGIC,
+            seq,
+            v0,
+            v1,
+            i0,
+            i1,
+            self.temp.unwrap_or(u8::MAX),
+            self.status.0,
+        ]
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Telemetry {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let mut s = serializer.serialize_struct("Telemetry", 4)?;
+        s.serialize_field("voltage_mv", &self.voltage_mv)?;
+        s.serialize_field("current_ma", &self.current_ma)?;
+        s.serialize_field("temp", &self.temp)?;
+        s.serialize_field("status", &self.status)?;
+        s.end()
+    }
+}
+
+/// Type of a PDO, see `SavedContract`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PdoKind {
+    Fixed,
+    Programmable,
+}
+
+/// A successful contract, to be stored across resets and requested again
+/// with `request_remembered()`, see `saved_contract()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SavedContract {
+    pub voltage_mv: u16,
+    pub current_ma: u16,
+    pub kind: PdoKind,
+}
+
+impl SavedContract {
+    /// Pack as voltage and current (little-endian u16) and the kind (0 for
+    /// fixed, 1 for programmable), e.g. to store it in flash.
+    pub fn to_bytes(&self) -> [u8; 5] {
+        let [v0, v1] = self.voltage_mv.to_le_bytes();
+        let [i0, i1] = self.current_ma.to_le_bytes();
+        [v0, v1, i0, i1, self.kind as u8]
+    }
+
+    /// Unpack the bytes from `to_bytes()`, `None` for an invalid kind, e.g.
+    /// erased flash.
+    pub fn from_bytes(bytes: [u8; 5]) -> Option<Self> {
+        let kind = match bytes[4] {
+            0 => PdoKind::Fixed,
+            1 => PdoKind::Programmable,
+            _ => return None,
+        };
+        Some(Self {
+            voltage_mv: u16::from_le_bytes([bytes[0], bytes[1]]),
+            current_ma: u16::from_le_bytes([bytes[2], bytes[3]]),
+            kind,
+        })
+    }
+}
+
+/// I2C transfer of the driver, reported to the callback set with
+/// `set_trace()` after it completed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum I2cTrace<'a> {
+    /// `data` was read from register `reg`
+    Read { reg: u8, data: &'a [u8] },
+    /// `data` was written to register `reg`
+    Write { reg: u8, data: &'a [u8] },
+}
+
+/// Raw values of all registers known to the driver, for diagnostics, see
+/// `dump_registers()`. Multi-byte registers are little-endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RegisterDump {
+    pub pdos: [u8; 28],
+    pub npdos: u8,
+    pub status: u8,
+    pub irqmask: u8,
+    pub voltage: u8,
+    pub current: u8,
+    /// `None` before the NTC table was written, see `read_temp()`
+    pub temp: Option<u8>,
+    pub ocpthr: u8,
+    pub otpthr: u8,
+    pub drthr: u8,
+    pub tr: [u8; 8],
+}
+
+/// Number of rising edges of the fault flags since start-up, see
+/// `fault_counters()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FaultCounters {
+    pub ovp: u32,
+    pub ocp: u32,
+    pub otp: u32,
+    pub derating: u32,
+}
+
+/// Temperature relative to the derating threshold, see `derating_info()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DeratingLevel {
+    /// Within `DERATING_MARGIN_C` below the threshold
+    Approaching,
+    /// At or above the threshold, the current should be reduced
+    Active,
+}
+
+/// Distance (°C) below the derating threshold reported as `Approaching`.
+pub const DERATING_MARGIN_C: u8 = 10;
+
+/// Coarse state of the PD link, e.g. for user feedback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LinkState {
+    /// No source attached or chip not ready
+    Disconnected,
+    /// Source attached, no contract established yet
+    Negotiating,
+    /// Contract established
+    Active,
+    /// Over-voltage, over-current or over-temperature protection triggered
+    Fault,
+}
+
+/// Lifecycle of the negotiation, advanced from the status register with
+/// `step()` independently of the driver, e.g. in a host test.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum NegotiationState {
+    /// Chip not ready, no source attached
+    #[default]
+    Idle,
+    /// Source attached and new PDOs advertised, a request is due
+    ReadingPdos,
+    /// Request sent, waiting for the source to accept it
+    Requested,
+    /// Contract established
+    Active,
+    /// Over-voltage, over-current or over-temperature protection triggered
+    Faulted,
+}
+
+impl NegotiationState {
+    /// Advance to the state indicated by `status` and return it. Faults
+    /// take precedence; a ready chip without new PDOs or success is taken
+    /// to wait for a request, except right after attaching, where the
+    /// read-clear new PDO flag may have been missed.
+    pub fn step(&mut self, status: Status) -> NegotiationState {
+        *self = if status.fault() {
+            NegotiationState::Faulted
+        } else if !status.ready() {
+            NegotiationState::Idle
+        } else if status.newpdos() || *self == NegotiationState::Idle {
+            NegotiationState::ReadingPdos
+        } else if status.success() {
+            NegotiationState::Active
+        } else {
+            NegotiationState::Requested
+        };
+        *self
+    }
+}
+
+/// Interrupt mask and protection thresholds, in the units of the
+/// individual accessors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceConfig {
+    pub irq_mask: IrqMask,
+    /// over-current threshold in mA, see `OCP_LSB_MA`
+    pub ocp_ma: u16,
+    /// over-temperature threshold in °C
+    pub otp_c: u8,
+    /// derating threshold in °C
+    pub dr_c: u8,
+}
+
+/// Initial configuration applied by `AP33772::init()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InitConfig {
+    /// NTC resistance table, see `write_tr()`
+    pub tr: [u8; 8],
+    pub config: DeviceConfig,
+}
+
+/// Whether a non-zero raw PDO looks like one a source can advertise: a
+/// decodable PDO with a consistent voltage range, or a battery, variable
+/// supply or AVS PDO, which are not decoded.
+fn plausible_pdo(raw: u32) -> bool {
+    match PDO::decode(raw) {
+        Some(pdo) => pdo.vmax() > 0 && pdo.vmin() <= pdo.vmax(),
+        None => matches!(raw >> 28, 0x4..=0xb | 0xd | 0xe),
+    }
+}
+
+/// Compute the NTC resistance table for `write_tr()` from the thermistor's
+/// resistance at 25 °C and its Beta value, using the Beta equation
+/// R(T) = R25 * exp(B * (1/T - 1/T25)). The resistances at 25, 50, 75 and
+/// 100 °C are packed as little-endian u16 in ohms. `None` if a resistance
+/// does not fit the 16-bit registers, e.g. for a 100k NTC.
+pub fn ntc_table(r25_ohms: u32, beta: u32) -> Option<[u8; 8]> {
+    const T25: f64 = 298.15;
+    let mut tr = [0; 8];
+    for (i, t) in [25.0, 50.0, 75.0, 100.0].iter().enumerate() {
+        let x = beta as f64 * (1.0 / (t + 273.15) - 1.0 / T25);
+        let r = r25_ohms as f64 * exp(x) + 0.5;
+        if r >= u16::MAX as f64 + 1.0 {
+            return None;
+        }
+        tr[2 * i..2 * (i + 1)].copy_from_slice(&(r as u16).to_le_bytes());
+    }
+    Some(tr)
+}
+
+/// Unpack the resistances (ohms) of an NTC table as written by `write_tr()`.
+fn tr_resistances(tr: [u8; 8]) -> [u16; 4] {
+    [0, 1, 2, 3].map(|i| u16::from_le_bytes([tr[2 * i], tr[2 * i + 1]]))
+}
+
+/// exp(x) for the small negative arguments of `ntc_table()`, `core` does
+/// not provide it.
+fn exp(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    for n in 1..32 {
+        term *= x / n as f64;
+        sum += term;
+    }
+    sum
+}
+
+/// Placeholder type for a driver without an output-enable pin.
+pub struct NoPin;
+
+impl ErrorType for NoPin {
+    type Error = Infallible;
+}
+
+impl OutputPin for NoPin {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Receives the driver's events, e.g. to report them over a serial link
+/// instead of defmt. Implemented for closures taking a `PdEvent`.
+pub trait PdObserver {
+    fn on_event(&mut self, event: PdEvent);
+}
+
+/// Placeholder type for a driver without an observer.
+pub struct NoObserver;
+
+impl PdObserver for NoObserver {
+    fn on_event(&mut self, _event: PdEvent) {}
+}
+
+impl<F: FnMut(PdEvent)> PdObserver for F {
+    fn on_event(&mut self, event: PdEvent) {
+        self(event)
+    }
+}
+
+/// Volume of events passed on by `Filtered`, in increasing order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Verbosity {
+    /// No events
+    Quiet,
+    /// The outcome of a negotiation, faults and resets
+    #[default]
+    Summary,
+    /// Every event, including new PDOs and the individual requests
+    Verbose,
+}
+
+/// Observer passing on only the events up to a `Verbosity`, e.g. to keep
+/// production logs to the negotiated contract.
+pub struct Filtered<OBS> {
+    verbosity: Verbosity,
+    observer: OBS,
+}
+
+impl<OBS: PdObserver> Filtered<OBS> {
+    pub fn new(verbosity: Verbosity, observer: OBS) -> Self {
+        Self {
+            verbosity,
+            observer,
+        }
+    }
+}
+
+impl<OBS: PdObserver> PdObserver for Filtered<OBS> {
+    fn on_event(&mut self, event: PdEvent) {
+        if event.verbosity() <= self.verbosity {
+            self.observer.on_event(event);
+        }
+    }
+}
+
+/// State of the driver that does not depend on its type parameters, so that
+/// the builders can move it as a whole.
+struct State {
+    addr: u8,
+    output_allowed: bool,
+    output_enabled: bool,
+    attached: bool,
+    prev_status: Status,
+    rdo: u32,
+    derating_percent: u8,
+    derated: bool,
+    retries: u8,
+    negotiation_timeout_ms: u32,
+    pending_since_ms: Option<u64>,
+    cable_current_limit_ma: Option<u16>,
+    max_power_mw: Option<u32>,
+    allow_pps: bool,
+    tr_written: bool,
+    pdos_stale: bool,
+    output_debounce: u8,
+    clear_reads: u8,
+    request_pending: bool,
+    contract_established: bool,
+    trace: Option<fn(I2cTrace)>,
+    fault_counters: FaultCounters,
+}
+
+/// Driver for the AP33772 with `N` PDO slots. The chip reports at most 7
+/// PDOs; a smaller `N` saves memory if fewer are of interest.
+///
+/// The AP33772S is not supported: it uses a different register map, 16-bit
+/// PDO and request formats and EPR, so it is not a superset of the
+/// AP33772 this driver could select with a type parameter.
+///
+/// Information about the source beyond its PDOs, e.g. the manufacturer
+/// VID/PID or battery status from extended PD messages, is not available:
+/// the AP33772 handles those messages internally and has no register
+/// exposing them. The PDOs from `read_pdos()` are all the driver can learn
+/// about the connected charger.
+pub struct AP33772<I2C, EN = NoPin, const N: usize = 7, OBS = NoObserver, IRQ = NoPin> {
     i2c: I2C,
+    en: Option<EN>,
     pub status: Status,
-    pub pdos: [Option<PDO>; 7],
+    pub pdos: [Option<PDO>; N],
+    state: State,
+    observer: OBS,
+    irq: IRQ,
 }
 
 impl<I2C: I2c> AP33772<I2C> {
+    /// Create a driver at the default address. `usb_dev` is any `I2c`: the
+    /// I2C peripheral itself if the AP33772 is the only device on the bus,
+    /// or a bus-sharing device, e.g. an `embedded_hal_bus` `RefCellDevice`,
+    /// when other devices are connected to the same I2C.
     pub fn new(usb_dev: I2C) -> Self {
+        Self::new_with_address(usb_dev, ADDR)
+    }
+
+    /// Create a driver for a chip at a non-default I2C address, e.g. for
+    /// several AP33772 sharing one bus.
+    pub fn new_with_address(usb_dev: I2C, addr: u8) -> Self {
+        Self::new_with_slots(usb_dev, addr)
+    }
+}
+
+impl<I2C: I2c, const N: usize> AP33772<I2C, NoPin, N> {
+    /// Create a driver with `N` (1 to 7) PDO slots, e.g.
+    /// `AP33772::<_, _, 4>::new_with_slots(i2c, addr)`.
+    pub fn new_with_slots(i2c: I2C, addr: u8) -> Self {
+        const { assert!(N >= 1 && N <= 7, "the AP33772 reports 1 to 7 PDOs") };
         Self {
-            i2c: usb_dev,
-            pdos: [None, None, None, None, None, None, None],
+            i2c,
+            en: None,
+            pdos: [None; N],
             status: Status(0),
+            state: State {
+                addr,
+                output_allowed: true,
+                output_enabled: false,
+                attached: false,
+                prev_status: Status(0),
+                rdo: 0,
+                derating_percent: 70,
+                derated: false,
+                retries: 3,
+                negotiation_timeout_ms: NEGOTIATION_TIMEOUT_MS,
+                pending_since_ms: None,
+                cable_current_limit_ma: None,
+                max_power_mw: None,
+                allow_pps: true,
+                tr_written: false,
+                pdos_stale: false,
+                output_debounce: 1,
+                clear_reads: u8::MAX,
+                request_pending: false,
+                contract_established: false,
+                trace: None,
+                fault_counters: FaultCounters::default(),
+            },
+            observer: NoObserver,
+            irq: NoPin,
+        }
+    }
+
+    /// Hand the output-enable pin (e.g. the VBUS load switch) to the driver,
+    /// which then gates it from `update()`, see `apply_output_policy()`.
+    pub fn with_enable_pin<EN: OutputPin>(self, pin: EN) -> AP33772<I2C, EN, N> {
+        AP33772 {
+            i2c: self.i2c,
+            en: Some(pin),
+            status: self.status,
+            pdos: self.pdos,
+            state: State {
+                output_enabled: false,
+                ..self.state
+            },
+            observer: self.observer,
+            irq: self.irq,
         }
     }
+}
+
+impl<I2C: I2c, EN: OutputPin, const N: usize> AP33772<I2C, EN, N> {
+    /// Report events (status changes, requests, rejections) to `observer`.
+    pub fn with_observer<OBS: PdObserver>(self, observer: OBS) -> AP33772<I2C, EN, N, OBS> {
+        AP33772 {
+            i2c: self.i2c,
+            en: self.en,
+            status: self.status,
+            pdos: self.pdos,
+            state: self.state,
+            observer,
+            irq: self.irq,
+        }
+    }
+}
 
-    pub fn update(&mut self) -> Result<(), I2C::Error> {
+#[cfg(feature = "async")]
+impl<I2C: I2c, EN: OutputPin, const N: usize, OBS: PdObserver> AP33772<I2C, EN, N, OBS> {
+    /// Hand the interrupt pin to the driver, see `wait_event()`. Boards
+    /// without it connected use `poll()` instead.
+    pub fn with_irq_pin<IRQ: Wait>(self, irq: IRQ) -> AP33772<I2C, EN, N, OBS, IRQ> {
+        AP33772 {
+            i2c: self.i2c,
+            en: self.en,
+            status: self.status,
+            pdos: self.pdos,
+            state: self.state,
+            observer: self.observer,
+            irq,
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<I2C: I2c, EN: OutputPin, const N: usize, OBS: PdObserver, IRQ: Wait>
+    AP33772<I2C, EN, N, OBS, IRQ>
+{
+    /// Wait for the interrupt line to go high, then `update()` and return
+    /// the event. Reading the status clears the interrupt. The interrupts
+    /// to wait for are selected with `write_irqmask()`.
+    pub async fn wait_event(&mut self) -> Result<PdEvent, Error<I2C::Error>> {
+        self.irq
+            .wait_for_high()
+            .await
+            .map_err(|_| Error::Interrupt)?;
+        self.update().map_err(Error::I2c)
+    }
+}
+
+impl<I2C: I2c, EN: OutputPin, const N: usize, OBS: PdObserver, IRQ> AP33772<I2C, EN, N, OBS, IRQ> {
+    /// Consume the driver and hand back the I2C bus, e.g. to use it for other
+    /// devices once the negotiation is done. The enable pin, if any, is
+    /// dropped in its current state.
+    pub fn release(self) -> I2C {
+        self.i2c
+    }
+
+    /// Read the status register, re-read the PDOs if new ones were advertised
+    /// and apply the output policy. Errors from the enable pin are ignored
+    /// here, call `apply_output_policy()` directly to observe them.
+    ///
+    /// When the chip is no longer ready the source is considered detached
+    /// and the PDOs and the last request are forgotten. If the PDOs cannot
+    /// be read completely, the previous ones are kept and the read is
+    /// repeated on the next call.
+    ///
+    /// If the link is lost while a request awaits success, the source is
+    /// taken to have reset the link (some sources hard-reset on an invalid
+    /// RDO): `PdEvent::SourceReset` is returned and the PDOs are re-read
+    /// as soon as the chip is ready again.
+    pub fn update(&mut self) -> Result<PdEvent, I2C::Error> {
+        self.state.prev_status = self.status;
         self.status.0 = self.read_status()?;
-        if self.status.ready() && self.status.newpdos() {
-            self.read_pdos()?;
+        let mut source_reset = false;
+        if self.state.attached && !self.status.ready() {
+            source_reset = self.state.request_pending;
+            self.pdos = [None; N];
+            self.state.pdos_stale = source_reset;
+            self.state.rdo = 0;
+            self.state.derated = false;
+            self.state.request_pending = false;
+            self.state.contract_established = false;
         }
-        Ok(())
+        self.state.attached = self.status.ready();
+        if self.status.ready() && self.status.success() {
+            self.state.contract_established |= self.state.request_pending;
+            self.state.request_pending = false;
+        }
+        let (status, prev) = (self.status, self.state.prev_status);
+        let counters = &mut self.state.fault_counters;
+        for (set, was_set, count) in [
+            (status.ovp(), prev.ovp(), &mut counters.ovp),
+            (status.ocp(), prev.ocp(), &mut counters.ocp),
+            (status.otp(), prev.otp(), &mut counters.otp),
+            (status.derating(), prev.derating(), &mut counters.derating),
+        ] {
+            if set && !was_set {
+                *count = count.saturating_add(1);
+            }
+        }
+        if self.status.fault() {
+            self.state.clear_reads = 0;
+        } else {
+            self.state.clear_reads = self.state.clear_reads.saturating_add(1);
+        }
+        if self.status.ready() && (self.status.newpdos() || self.state.pdos_stale) {
+            match self.read_pdos() {
+                Ok(_) => self.state.pdos_stale = false,
+                Err(Error::I2c(e)) => return Err(e),
+                Err(_) => self.state.pdos_stale = true,
+            }
+        }
+        let _ = self.apply_output_policy();
+        let event = if source_reset {
+            PdEvent::SourceReset
+        } else {
+            PdEvent::from_status(&self.status)
+        };
+        if self.status_changed() && event != PdEvent::Nothing {
+            self.observer.on_event(event);
+        }
+        Ok(event)
     }
 
-    fn read_buf<const N: usize>(&mut self, wbuf: &[u8]) -> Result<[u8; N], I2C::Error> {
-        let mut buf = [0; N];
-        self.i2c.write_read(ADDR, wbuf, &mut buf)?;
-        Ok(buf)
+    /// Set the time after which `tick()` re-issues a pending request,
+    /// defaults to 1 s.
+    pub fn set_negotiation_timeout(&mut self, timeout_ms: u32) {
+        self.state.negotiation_timeout_ms = timeout_ms;
     }
 
-    pub fn read_pdos(&mut self) -> Result<[u32; 7], I2C::Error> {
-        let buf: [u8; 28] = self.read_buf(&[0x0])?;
-        let mut pdos = [0u32; 7];
-        for i in 0..7 {
-            let pdo: &[u8; 4] = &buf[4 * i..4 * (i + 1)].try_into().unwrap();
-            pdos[i] = u32::from_le_bytes(*pdo);
-            self.pdos[i] = if pdos[i] == 0x0 {
-                None
-            } else if pdos[i] & 0xf000_0000 == 0xc000_0000 {
-                Some(PDO::Programmable(APDO(pdos[i])))
-            } else if pdos[i] & 0xc000_0000 == 0x0 {
-                Some(PDO::Fixed(FixedPDO(pdos[i])))
-            } else {
-                None
+    /// Watchdog for hung negotiations, to be called periodically after
+    /// `update()` with a monotonic time in ms. If a request is pending and
+    /// no contract has been established since the source attached within
+    /// the negotiation timeout, it is re-issued. Returns whether the request
+    /// was re-issued.
+    pub fn tick(&mut self, now_ms: u64) -> Result<bool, I2C::Error> {
+        if self.state.rdo == 0
+            || !self.state.attached
+            || !self.state.request_pending
+            || self.state.contract_established
+        {
+            self.state.pending_since_ms = None;
+            return Ok(false);
+        }
+        let since = *self.state.pending_since_ms.get_or_insert(now_ms);
+        if now_ms.saturating_sub(since) < self.state.negotiation_timeout_ms as u64 {
+            return Ok(false);
+        }
+        self.state.pending_since_ms = Some(now_ms);
+        self.renegotiate()
+    }
+
+    /// Wait `interval_ms`, then `update()` and return the status, for boards
+    /// without the interrupt line connected. The driver does not depend on
+    /// the interrupt line, in polling mode call this in a loop, e.g.
+    /// `loop { pdc.poll(&mut delay, 100)?; pdc.tick(now_ms)?; }`, and
+    /// react to the returned status as to an interrupt.
+    pub fn poll(
+        &mut self,
+        delay: &mut impl DelayNs,
+        interval_ms: u32,
+    ) -> Result<Status, I2C::Error> {
+        delay.delay_ms(interval_ms);
+        self.update()?;
+        Ok(self.status)
+    }
+
+    /// Poll the status until the chip reports ready, e.g. after power-up,
+    /// for at most `timeout_ms`. Returns whether it became ready.
+    pub fn wait_ready(
+        &mut self,
+        delay: &mut impl DelayNs,
+        timeout_ms: u32,
+    ) -> Result<bool, I2C::Error> {
+        let mut elapsed_ms = 0;
+        loop {
+            self.update()?;
+            if self.status.ready() {
+                return Ok(true);
+            }
+            if elapsed_ms >= timeout_ms {
+                return Ok(false);
+            }
+            delay.delay_ms(POLL_INTERVAL_MS);
+            elapsed_ms += POLL_INTERVAL_MS;
+        }
+    }
+
+    /// Poll the status after a request until the negotiation succeeded, a
+    /// fault occurred or `timeout_ms` elapsed. Returns whether the
+    /// negotiation succeeded.
+    ///
+    /// The AP33772 has no explicit reject flag: if the chip is still ready
+    /// but has not reported success at the timeout, the source is taken to
+    /// have rejected the request and `Error::NegotiationRejected` is
+    /// returned, e.g. to fall back to another PDO.
+    pub fn await_negotiation(
+        &mut self,
+        delay: &mut impl DelayNs,
+        timeout_ms: u32,
+    ) -> Result<bool, Error<I2C::Error>> {
+        let mut elapsed_ms = 0;
+        loop {
+            match self.update().map_err(Error::I2c)? {
+                PdEvent::Fault(kind) if kind != FaultKind::Derating => return Ok(false),
+                _ if self.status.ready() && self.status.success() => return Ok(true),
+                _ => {}
+            }
+            if elapsed_ms >= timeout_ms {
+                if self.status.ready() {
+                    self.observer.on_event(PdEvent::Rejected);
+                    return Err(Error::NegotiationRejected);
+                }
+                return Ok(false);
+            }
+            delay.delay_ms(POLL_INTERVAL_MS);
+            elapsed_ms += POLL_INTERVAL_MS;
+        }
+    }
+
+    /// Voltage (mV) and current (mA) of the contract in force. The AP33772
+    /// has no register holding the contract accepted by the source, so this
+    /// reads nothing: it is derived from the cached PDOs and the last RDO
+    /// written, at the reduced current while derated, see
+    /// `handle_derating()`. `None` if the cached status does not indicate a
+    /// successful negotiation, see `read_active_contract()` to refresh it.
+    pub fn active_contract(&self) -> Option<(u16, u16)> {
+        if self.state.rdo == 0 || !(self.status.ready() && self.status.success()) {
+            return None;
+        }
+        let rdo = if self.state.derated {
+            self.derated_rdo()?
+        } else {
+            self.state.rdo
+        };
+        match self.requested_pdo(rdo)? {
+            pdo @ PDO::Fixed(_) => {
+                let i = FixedRDO(rdo).current_ma();
+                Some((pdo.vmax() as u16, i as u16))
+            }
+            PDO::Programmable(_) => {
+                let ardo = ARDO(rdo);
+                Some((ardo.voltage_mv() as u16, ardo.current_ma() as u16))
+            }
+        }
+    }
+
+    /// `active_contract()` after reading the status with `update()`.
+    pub fn read_active_contract(&mut self) -> Result<Option<(u16, u16)>, I2C::Error> {
+        self.update()?;
+        Ok(self.active_contract())
+    }
+
+    /// The active contract in the form stored by `request_remembered()`,
+    /// `None` without a contract, see `active_contract()`.
+    pub fn saved_contract(&self) -> Option<SavedContract> {
+        let (voltage_mv, current_ma) = self.active_contract()?;
+        let kind = match self.requested_pdo(self.state.rdo)? {
+            PDO::Fixed(_) => PdoKind::Fixed,
+            PDO::Programmable(_) => PdoKind::Programmable,
+        };
+        Some(SavedContract {
+            voltage_mv,
+            current_ma,
+            kind,
+        })
+    }
+
+    /// Current (mA) of the active contract minus the measured VBUS current,
+    /// e.g. to check before enabling a heavy load. Negative if the load
+    /// already exceeds the contract, `None` without a contract, see
+    /// `active_contract()`.
+    pub fn contract_headroom_ma(&mut self) -> Result<Option<i32>, I2C::Error> {
+        let Some((_, contract_ma)) = self.active_contract() else {
+            return Ok(None);
+        };
+        let current_ma = self.read_current()?;
+        Ok(Some(contract_ma as i32 - current_ma as i32))
+    }
+
+    /// Whether the measured VBUS voltage is within `tolerance_mv` of the
+    /// voltage of the active contract, to catch a source that accepts a
+    /// request but delivers a different voltage. `false` without a
+    /// contract, see `active_contract()`. The measurement has a resolution
+    /// of `VOLTAGE_LSB_MV`. The output is left alone, callers should use
+    /// `allow_output(false)` on a mismatch.
+    pub fn verify_output(&mut self, tolerance_mv: u16) -> Result<bool, Error<I2C::Error>> {
+        let Some((contract_mv, _)) = self.active_contract() else {
+            return Ok(false);
+        };
+        let voltage_mv = self.read_voltage().map_err(Error::I2c)?;
+        Ok(voltage_mv.abs_diff(contract_mv) <= tolerance_mv)
+    }
+
+    /// Number of OVP, OCP, OTP and derating events seen by `update()` since
+    /// start-up, counting the status reads on which a flag became set.
+    pub fn fault_counters(&self) -> FaultCounters {
+        self.state.fault_counters
+    }
+
+    /// Whether the last `update()` read a status different from the one
+    /// before, callers can use this to skip redundant processing.
+    pub fn status_changed(&self) -> bool {
+        self.status != self.state.prev_status
+    }
+
+    /// Set how often `retry()` re-attempts a failed transaction, defaults
+    /// to 3.
+    pub fn set_retries(&mut self, retries: u8) {
+        self.state.retries = retries;
+    }
+
+    /// Run `f` and re-attempt it on I2C errors, e.g. transient NAKs while
+    /// the chip is busy negotiating. The delay between attempts starts at
+    /// `RETRY_BACKOFF_MS` and doubles every time, up to
+    /// `RETRY_BACKOFF_MAX_MS`. Returns the last error if all retries fail.
+    pub fn retry<T>(
+        &mut self,
+        delay: &mut impl DelayNs,
+        mut f: impl FnMut(&mut Self) -> Result<T, I2C::Error>,
+    ) -> Result<T, I2C::Error> {
+        let mut backoff_ms = RETRY_BACKOFF_MS;
+        let mut attempt = 0;
+        loop {
+            match f(self) {
+                Err(_) if attempt < self.state.retries => {
+                    attempt += 1;
+                    delay.delay_ms(backoff_ms);
+                    backoff_ms = backoff_ms.saturating_mul(2).min(RETRY_BACKOFF_MAX_MS);
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// `update()` with retries on I2C errors, see `retry()`.
+    pub fn update_retry(&mut self, delay: &mut impl DelayNs) -> Result<PdEvent, I2C::Error> {
+        self.retry(delay, |pdc| pdc.update())
+    }
+
+    /// `write_rdo()` with retries on I2C errors, see `retry()`.
+    pub fn write_rdo_retry(
+        &mut self,
+        rdo: &RDO,
+        delay: &mut impl DelayNs,
+    ) -> Result<(), I2C::Error> {
+        self.retry(delay, |pdc| pdc.write_rdo(rdo))
+    }
+
+    /// Allow or inhibit the output independent of the status. While allowed,
+    /// the output policy decides about the enable pin.
+    pub fn allow_output(&mut self, allow: bool) -> Result<bool, EN::Error> {
+        self.state.output_allowed = allow;
+        self.apply_output_policy()
+    }
+
+    /// Report every I2C transfer to `trace`, e.g. to log the traffic with
+    /// defmt while debugging a misbehaving source. `None` (the default)
+    /// disables tracing.
+    pub fn set_trace(&mut self, trace: Option<fn(I2cTrace)>) {
+        self.state.trace = trace;
+    }
+
+    /// Require `reads` (at least 1, the default) consecutive status reads
+    /// without OVP, OCP or OTP before the output is re-enabled after a
+    /// fault, to avoid chattering of the enable pin on marginal faults.
+    pub fn set_output_debounce(&mut self, reads: u8) {
+        self.state.output_debounce = reads.max(1);
+    }
+
+    /// Drive the enable pin from the last status: de-assert on OVP, OCP or
+    /// OTP and when the source is detached, assert once the chip is ready,
+    /// the negotiation succeeded and the fault has been clear for the
+    /// number of reads set with `set_output_debounce()`. Returns whether the
+    /// output is enabled.
+    ///
+    /// The output is only enabled once the driver itself has written an RDO
+    /// and seen it succeed since the source attached, so that a success flag
+    /// left over from before start-up does not enable the output at the
+    /// default 5 V profile.
+    pub fn apply_output_policy(&mut self) -> Result<bool, EN::Error> {
+        let Some(pin) = self.en.as_mut() else {
+            return Ok(false);
+        };
+        if !self.state.output_allowed
+            || !self.state.attached
+            || self.status.ovp()
+            || self.status.ocp()
+            || self.status.otp()
+        {
+            pin.set_low()?;
+            self.state.output_enabled = false;
+        } else if self.state.contract_established
+            && self.status.ready()
+            && self.status.success()
+            && self.state.clear_reads >= self.state.output_debounce
+        {
+            pin.set_high()?;
+            self.state.output_enabled = true;
+        }
+        Ok(self.state.output_enabled)
+    }
+
+    /// Whether a source was attached as of the last `update()`.
+    pub fn is_attached(&self) -> bool {
+        self.state.attached
+    }
+
+    /// Whether the enable pin is currently asserted.
+    pub fn output_enabled(&self) -> bool {
+        self.state.output_enabled
+    }
+
+    /// State of the link as of the last `update()`.
+    pub fn link_state(&self) -> LinkState {
+        if self.status.fault() {
+            LinkState::Fault
+        } else if !self.status.ready() {
+            LinkState::Disconnected
+        } else if self.status.success() {
+            LinkState::Active
+        } else {
+            LinkState::Negotiating
+        }
+    }
+
+    /// Iterate over the advertised PDOs together with their 1-based object
+    /// position.
+    pub fn valid_pdos(&self) -> impl Iterator<Item = (usize, &PDO)> {
+        self.pdos
+            .iter()
+            .enumerate()
+            .filter_map(|(i, pdo)| pdo.as_ref().map(|pdo| (i + 1, pdo)))
+    }
+
+    /// Iterate over the advertised fixed PDOs together with their 1-based
+    /// object position.
+    pub fn fixed_pdos(&self) -> impl Iterator<Item = (usize, &FixedPDO)> {
+        self.valid_pdos().filter_map(|(pos, pdo)| match pdo {
+            PDO::Fixed(fixed) => Some((pos, fixed)),
+            PDO::Programmable(_) => None,
+        })
+    }
+
+    /// Iterate over the advertised programmable (PPS) PDOs together with
+    /// their 1-based object position.
+    pub fn pps_pdos(&self) -> impl Iterator<Item = (usize, &APDO)> {
+        self.valid_pdos().filter_map(|(pos, pdo)| match pdo {
+            PDO::Programmable(apdo) => Some((pos, apdo)),
+            PDO::Fixed(_) => None,
+        })
+    }
+
+    /// Whether the source advertises at least one programmable (PPS) PDO.
+    pub fn supports_pps(&self) -> bool {
+        self.pps_pdos().next().is_some()
+    }
+
+    /// Limit the current of all requests to the rating of the cable, e.g.
+    /// 3 A for cables without an e-marker. `None` (the default) only
+    /// limits to the current advertised by the PDO.
+    pub fn set_cable_current_limit(&mut self, limit_ma: Option<u16>) {
+        self.state.cable_current_limit_ma = limit_ma;
+    }
+
+    /// Limit the power of all requests, e.g. for a battery-powered sink:
+    /// the requested current is reduced so that voltage times current does
+    /// not exceed `limit_mw`. `None` (the default) disables the budget.
+    pub fn set_max_power(&mut self, limit_mw: Option<u32>) {
+        self.state.max_power_mw = limit_mw;
+    }
+
+    /// Whether PDO selection may pick programmable PDOs, defaults to
+    /// `true`. With `false`, `select_pdo()`, `negotiate()`,
+    /// `request_voltage()` and `request_max_power()` only consider fixed
+    /// PDOs, e.g. for a rail that must not follow a drifting PPS voltage.
+    /// Explicit `request_pps()` calls are not affected.
+    pub fn set_allow_pps(&mut self, allow: bool) {
+        self.state.allow_pps = allow;
+    }
+
+    /// Whether `pdo` may be chosen by PDO selection, see `set_allow_pps()`.
+    fn selectable(&self, pdo: &PDO) -> bool {
+        self.state.allow_pps || !matches!(pdo, PDO::Programmable(_))
+    }
+
+    /// Current that may be requested from `pdo` at `voltage_mv`, considering
+    /// the cable and the power budget.
+    fn max_current_ma(&self, pdo: &PDO, voltage_mv: u32) -> u32 {
+        let mut imax = pdo.imax();
+        if let Some(limit) = self.state.cable_current_limit_ma {
+            imax = imax.min(limit as u32);
+        }
+        if let Some(budget) = self.state.max_power_mw {
+            let budget_ma = (budget as u64 * 1000).checked_div(voltage_mv as u64);
+            imax = imax.min(budget_ma.map_or(imax, |i| i.min(u32::MAX as u64) as u32));
+        }
+        imax
+    }
+
+    /// Find the index (into `pdos`) of the PDO compatible with `req` that is
+    /// preferred by `policy`.
+    pub fn select_pdo(&self, req: &PowerRequest, policy: SelectionPolicy) -> Option<usize> {
+        let mut sel: Option<(usize, &PDO)> = None;
+        for (i, pdo_opt) in self.pdos.iter().enumerate() {
+            let Some(pdo) = pdo_opt.as_ref().filter(|pdo| self.selectable(pdo)) else {
+                continue;
+            };
+            if !(pdo.vcomp(req.v_min.into(), req.v_max.into())
+                && req.i_min as u32 <= self.max_current_ma(pdo, pdo.vmin().max(req.v_min.into())))
+            {
+                continue;
+            }
+            if sel.is_none_or(|(_, best)| pdo.better_than(best, policy, req.v_nom.into())) {
+                sel = Some((i, pdo));
+            }
+        }
+        sel.map(|(i, _)| i)
+    }
+
+    /// Find the index (into `pdos`) of a PDO able to supply `voltage_mv` at
+    /// `current_ma`, without requesting it.
+    pub fn can_supply(&self, voltage_mv: u16, current_ma: u16) -> Option<usize> {
+        let req = PowerRequest {
+            v_nom: voltage_mv,
+            v_min: voltage_mv,
+            v_max: voltage_mv,
+            i_nom: current_ma,
+            i_min: current_ma,
+        };
+        self.select_pdo(&req, SelectionPolicy::default())
+    }
+
+    /// Select a PDO for `req` according to `policy` and request it, returns
+    /// the object position of the requested PDO.
+    pub fn negotiate(
+        &mut self,
+        req: &PowerRequest,
+        policy: SelectionPolicy,
+    ) -> Result<PdoPosition, Error<I2C::Error>> {
+        let ipdo = self.select_pdo(req, policy).ok_or(Error::NoCompatiblePdo)?;
+        match self.pdos[ipdo] {
+            Some(PDO::Programmable(_)) => {
+                let v_set = req.v_nom.clamp(req.v_min, req.v_max);
+                self.request_pps(ipdo, v_set, req.i_nom)?;
+            }
+            _ => self.request_fixed(ipdo, req.i_nom)?,
+        }
+        PdoPosition::from_index(ipdo).ok_or(Error::InvalidPdoIndex)
+    }
+
+    /// Try the requests in `reqs` in order: select and request a PDO with
+    /// `negotiate()` and wait for the result with `await_negotiation()`,
+    /// moving on to the next request if no PDO is compatible or the request
+    /// is not accepted (an attempt ending in a fault or timeout counts as
+    /// rejected). Returns the index of the satisfied request and the
+    /// position of the requested PDO, otherwise the error of the last
+    /// attempt.
+    pub fn negotiate_fallback(
+        &mut self,
+        reqs: &[PowerRequest],
+        policy: SelectionPolicy,
+        delay: &mut impl DelayNs,
+        timeout_ms: u32,
+    ) -> Result<(usize, PdoPosition), Error<I2C::Error>> {
+        let mut err = Error::NoCompatiblePdo;
+        for (i, req) in reqs.iter().enumerate() {
+            let pos = match self.negotiate(req, policy) {
+                Ok(pos) => pos,
+                Err(e @ Error::I2c(_)) => return Err(e),
+                Err(e) => {
+                    err = e;
+                    continue;
+                }
+            };
+            match self.await_negotiation(delay, timeout_ms) {
+                Ok(true) => return Ok((i, pos)),
+                Ok(false) => err = Error::NegotiationRejected,
+                Err(e @ Error::I2c(_)) => return Err(e),
+                Err(e) => err = e,
+            }
+        }
+        Err(err)
+    }
+
+    /// Request precisely `voltage_mv` at the maximum current, for loads that
+    /// must not see any other voltage: a fixed PDO of exactly that voltage
+    /// or a programmable PDO whose range includes it and whose request
+    /// resolution (`ARDO_V_LSB_MV`) can represent it, see
+    /// `request_voltage()`. Never falls back to a nearby voltage.
+    pub fn request_exact_voltage(
+        &mut self,
+        voltage_mv: u16,
+    ) -> Result<PdoPosition, Error<I2C::Error>> {
+        let v = voltage_mv as u32;
+        let (ipdo, pdo) = self.pdo_for_voltage(v).ok_or(Error::NoCompatiblePdo)?;
+        match pdo {
+            PDO::Fixed(_) => self.request_fixed(ipdo, pdo.imax() as u16)?,
+            PDO::Programmable(_) if v.is_multiple_of(ARDO_V_LSB_MV) => {
+                self.request_pps(ipdo, voltage_mv, pdo.imax() as u16)?
+            }
+            PDO::Programmable(_) => return Err(Error::NoCompatiblePdo),
+        }
+        PdoPosition::from_index(ipdo).ok_or(Error::InvalidPdoIndex)
+    }
+
+    /// Request `voltage_mv` from a fixed PDO of exactly that voltage or,
+    /// failing that, from the programmable PDO with the highest current
+    /// whose range includes it. The current is clamped to the PDO.
+    pub fn request_voltage(
+        &mut self,
+        voltage_mv: u16,
+        current_ma: u16,
+    ) -> Result<PdoPosition, Error<I2C::Error>> {
+        let (ipdo, pdo) = self
+            .pdo_for_voltage(voltage_mv as u32)
+            .ok_or(Error::NoCompatiblePdo)?;
+        match pdo {
+            PDO::Fixed(_) => self.request_fixed(ipdo, current_ma)?,
+            PDO::Programmable(_) => self.request_pps(ipdo, voltage_mv, current_ma)?,
+        }
+        PdoPosition::from_index(ipdo).ok_or(Error::InvalidPdoIndex)
+    }
+
+    /// Request `saved` from the current PDOs without selecting among them,
+    /// e.g. to restore the contract from before a reset: a fixed PDO of the
+    /// saved voltage or a programmable PDO including it, of the saved kind
+    /// and able to supply the saved current. Returns `false` without
+    /// requesting anything if no such PDO is advertised (any more).
+    pub fn request_remembered(&mut self, saved: SavedContract) -> Result<bool, Error<I2C::Error>> {
+        let v = saved.voltage_mv as u32;
+        let found = self.valid_pdos().find(|(_, pdo)| {
+            let kind_matches = match (saved.kind, pdo) {
+                (PdoKind::Fixed, PDO::Fixed(_)) => pdo.vmax() == v,
+                (PdoKind::Programmable, PDO::Programmable(_)) => pdo.vcomp(v, v),
+                _ => false,
             };
+            kind_matches && self.selectable(pdo) && saved.current_ma as u32 <= pdo.imax()
+        });
+        let Some((pos, pdo)) = found.map(|(pos, pdo)| (pos, *pdo)) else {
+            return Ok(false);
+        };
+        match pdo {
+            PDO::Fixed(_) => self.request_fixed(pos - 1, saved.current_ma)?,
+            PDO::Programmable(_) => {
+                self.request_pps(pos - 1, saved.voltage_mv, saved.current_ma)?
+            }
+        }
+        Ok(true)
+    }
+
+    /// Index (into `pdos`) and PDO for `request_voltage()`.
+    fn pdo_for_voltage(&self, v: u32) -> Option<(usize, PDO)> {
+        let fixed = self
+            .valid_pdos()
+            .find(|(_, pdo)| matches!(pdo, PDO::Fixed(_)) && pdo.vmax() == v);
+        let pps = || {
+            self.valid_pdos()
+                .filter(|(_, pdo)| matches!(pdo, PDO::Programmable(_)) && pdo.vcomp(v, v))
+                .filter(|(_, pdo)| self.selectable(pdo))
+                .max_by_key(|(pos, pdo)| (pdo.imax(), core::cmp::Reverse(*pos)))
+        };
+        fixed.or_else(pps).map(|(pos, pdo)| (pos - 1, *pdo))
+    }
+
+    /// Request the PDO with the highest `max_power_mw()` at its maximum
+    /// voltage and current, returns its object position or `None` if no
+    /// PDOs are advertised.
+    pub fn request_max_power(&mut self) -> Result<Option<PdoPosition>, Error<I2C::Error>> {
+        let mut sel: Option<(usize, PDO)> = None;
+        for (i, pdo) in self.pdos.iter().enumerate() {
+            match (pdo.as_ref().filter(|pdo| self.selectable(pdo)), sel) {
+                (Some(pdo), Some((_, pdo_sel))) if pdo.max_power_mw() <= pdo_sel.max_power_mw() => {
+                }
+                (Some(pdo), _) => sel = Some((i, *pdo)),
+                (None, _) => {}
+            }
+        }
+        let Some((ipdo, pdo)) = sel else {
+            return Ok(None);
+        };
+        match pdo {
+            PDO::Programmable(_) => self.request_pps(ipdo, pdo.vmax() as u16, pdo.imax() as u16)?,
+            PDO::Fixed(_) => self.request_fixed(ipdo, pdo.imax() as u16)?,
+        }
+        Ok(PdoPosition::from_index(ipdo))
+    }
+
+    /// Number of advertised PDOs, as decoded by the last `read_pdos`.
+    pub fn pdo_count(&self) -> usize {
+        self.pdos.iter().flatten().count()
+    }
+
+    /// Read `L` bytes from register `reg`. This relies on the HAL reporting
+    /// an error for a NAK'd or truncated transfer, a bus that returns `Ok`
+    /// with fewer bytes leaves the remainder 0.
+    fn read_buf<const L: usize>(&mut self, reg: u8) -> Result<[u8; L], I2C::Error> {
+        let mut buf = [0; L];
+        self.read_register(reg, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Read the single-byte registers `regs`, one transaction each. The
+    /// AP33772 addresses registers by command code and does not advance to
+    /// the next command code within a transfer, so multi-byte transfers are
+    /// only valid within one register (PDO, RDO, TR).
+    fn read_each<const L: usize>(&mut self, regs: [u8; L]) -> Result<[u8; L], I2C::Error> {
+        let mut buf = [0; L];
+        for (reg, val) in regs.into_iter().zip(buf.iter_mut()) {
+            self.read_register(reg, core::slice::from_mut(val))?;
         }
-        Ok(pdos)
+        Ok(buf)
+    }
+
+    /// Read `buf.len()` bytes starting at register `reg`, e.g. to inspect
+    /// registers not wrapped by the driver.
+    pub fn read_register(&mut self, reg: u8, buf: &mut [u8]) -> Result<(), I2C::Error> {
+        self.i2c.write_read(self.state.addr, &[reg], buf)?;
+        if let Some(trace) = self.state.trace {
+            trace(I2cTrace::Read { reg, data: buf });
+        }
+        Ok(())
+    }
+
+    /// Write `data` starting at register `reg`.
+    pub fn write_register(&mut self, reg: u8, data: &[u8]) -> Result<(), I2C::Error> {
+        self.i2c.transaction(
+            self.state.addr,
+            &mut [Operation::Write(&[reg]), Operation::Write(data)],
+        )?;
+        if let Some(trace) = self.state.trace {
+            trace(I2cTrace::Write { reg, data });
+        }
+        Ok(())
+    }
+
+    /// Write the register command code `buf[0]` followed by the data in a
+    /// single write.
+    fn write_raw(&mut self, buf: &[u8]) -> Result<(), I2C::Error> {
+        self.i2c.write(self.state.addr, buf)?;
+        if let (Some(trace), [reg, data @ ..]) = (self.state.trace, buf) {
+            trace(I2cTrace::Write { reg: *reg, data });
+        }
+        Ok(())
+    }
+
+    /// Read and decode the PDOs advertised by the source into `pdos`, see
+    /// `read_pdos_raw()`, and return them. On `Error::ShortRead` the
+    /// previous PDOs are kept.
+    pub fn read_pdos(&mut self) -> Result<[Option<PDO>; N], Error<I2C::Error>> {
+        self.pdos = self.read_pdos_raw()?.map(PDO::decode);
+        Ok(self.pdos)
+    }
+
+    /// Read the raw PDOs, as many as reported by `read_npdos()`; the
+    /// remaining slots are 0. The AP33772 only exposes the (up to 7) SPR
+    /// PDOs, EPR PDOs are not accessible. PDOs beyond the `N` slots are
+    /// ignored. Unlike `read_pdos()` this leaves `pdos` untouched.
+    ///
+    /// A reported PDO is never 0, so an all-zero slot within the count
+    /// indicates a truncated or corrupted transfer and `Error::ShortRead`
+    /// is returned instead. Likewise, `Error::PdoCountMismatch` is returned
+    /// if a slot within the count holds no plausible PDO.
+    pub fn read_pdos_raw(&mut self) -> Result<[u32; N], Error<I2C::Error>> {
+        let npdos = (self.read_npdos().map_err(Error::I2c)? as usize).min(N);
+        let mut buf = [[0u8; 4]; N];
+        if npdos > 0 {
+            self.read_register(0x00, buf[..npdos].as_flattened_mut())
+                .map_err(Error::I2c)?;
+        }
+        let raw = buf.map(u32::from_le_bytes);
+        if raw[..npdos].contains(&0) {
+            return Err(Error::ShortRead);
+        }
+        if !raw[..npdos].iter().all(|&pdo| plausible_pdo(pdo)) {
+            return Err(Error::PdoCountMismatch);
+        }
+        Ok(raw)
     }
 
     pub fn read_irqmask(&mut self) -> Result<u8, I2C::Error> {
-        let mut buf = [0];
-        self.i2c.write_read(ADDR, &[0x1e], &mut buf)?;
-        Ok(buf[0])
+        Ok(self.read_buf::<1>(0x1e)?[0])
     }
 
     pub fn write_irqmask(&mut self, mask: u8) -> Result<(), I2C::Error> {
-        self.i2c.write(ADDR, &[0x1e, mask])
+        self.write_raw(&[0x1e, mask])
     }
 
     pub fn read_npdos(&mut self) -> Result<u8, I2C::Error> {
-        let mut buf = [0];
-        self.i2c.write_read(ADDR, &[0x1c], &mut buf)?;
-        Ok(buf[0])
+        Ok(self.read_buf::<1>(0x1c)?[0])
     }
 
     fn read_status(&mut self) -> Result<u8, I2C::Error> {
-        let mut buf = [0];
-        self.i2c.write_read(ADDR, &[0x1d], &mut buf)?;
-        Ok(buf[0])
+        Ok(self.read_buf::<1>(0x1d)?[0])
+    }
+
+    /// Read voltage, current and temperature, followed by `update()` for
+    /// the status. The event can be recovered with `PdEvent::from_status()`.
+    pub fn measure_all(&mut self) -> Result<Telemetry, I2C::Error> {
+        let buf = self.read_each([0x20, 0x21, 0x22])?;
+        self.update()?;
+        Ok(Telemetry {
+            voltage_mv: raw_to_mv(buf[0]),
+            current_ma: raw_to_ma(buf[1]),
+            temp: self.state.tr_written.then_some(buf[2]),
+            status: self.status,
+        })
     }
 
+    /// VBUS voltage in mV. The register is 8 bits wide with an LSB of
+    /// 80 mV, so the result is at most 20400 mV and cannot overflow.
     pub fn read_voltage(&mut self) -> Result<u16, I2C::Error> {
-        let mut buf = [0];
-        self.i2c.write_read(ADDR, &[0x20], &mut buf)?;
-        Ok(buf[0] as u16 * 80)
+        let buf = self.read_buf::<1>(0x20)?;
+        Ok(raw_to_mv(buf[0]))
     }
 
+    /// VBUS current in mA. The register is 8 bits wide with an LSB of
+    /// 24 mA, so the result is at most 6120 mA and cannot overflow.
     pub fn read_current(&mut self) -> Result<u16, I2C::Error> {
-        let buf = self.read_buf::<1>(&[0x21])?;
-        Ok(buf[0] as u16 * 24)
+        let buf = self.read_buf::<1>(0x21)?;
+        Ok(raw_to_ma(buf[0]))
+    }
+
+    /// Mean of `samples` voltage readings (at least one) in mV.
+    pub fn read_voltage_avg(&mut self, samples: u8) -> Result<u16, I2C::Error> {
+        self.read_avg(0x20, samples).map(|v| v * VOLTAGE_LSB_MV)
+    }
+
+    /// Mean of `samples` current readings (at least one) in mA.
+    pub fn read_current_avg(&mut self, samples: u8) -> Result<u16, I2C::Error> {
+        self.read_avg(0x21, samples).map(|v| v * CURRENT_LSB_MA)
+    }
+
+    /// Mean of `samples` raw readings of register `reg`, rounded.
+    fn read_avg(&mut self, reg: u8, samples: u8) -> Result<u16, I2C::Error> {
+        let n = samples.max(1) as u32;
+        let mut sum = 0;
+        for _ in 0..n {
+            sum += self.read_buf::<1>(reg)?[0] as u32;
+        }
+        Ok(((sum + n / 2) / n) as u16)
     }
 
-    pub fn read_temp(&mut self) -> Result<u8, I2C::Error> {
-        let mut buf = [0];
-        self.i2c.write_read(ADDR, &[0x22], &mut buf)?;
-        Ok(buf[0])
+    /// Temperature in °C. Without the NTC table matching the thermistor the
+    /// reading is meaningless (or the NTC is not even populated), so this
+    /// returns `Error::NtcNotConfigured` until `write_tr()` succeeded.
+    pub fn read_temp(&mut self) -> Result<u8, Error<I2C::Error>> {
+        if !self.state.tr_written {
+            return Err(Error::NtcNotConfigured);
+        }
+        Ok(self.read_buf::<1>(0x22).map_err(Error::I2c)?[0])
     }
 
-    pub fn write_ocpthr(&mut self, thr: u16) -> Result<(), I2C::Error> {
-        let val: u8 = (thr / 50).try_into().unwrap();
-        self.i2c.write(ADDR, &[0x23, val])
+    /// Set the over-current threshold in mA, in steps of `OCP_LSB_MA` up to
+    /// 12750 mA, `Error::ValueOutOfRange` above.
+    pub fn write_ocpthr(&mut self, thr: u16) -> Result<(), Error<I2C::Error>> {
+        let val = u8::try_from(thr / OCP_LSB_MA).map_err(|_| Error::ValueOutOfRange)?;
+        self.write_raw(&[0x23, val]).map_err(Error::I2c)
     }
 
     pub fn write_otpthr(&mut self, thr: u8) -> Result<(), I2C::Error> {
-        self.i2c.write(ADDR, &[0x24, thr])
+        self.write_raw(&[0x24, thr])
     }
 
     pub fn write_drthr(&mut self, thr: u8) -> Result<(), I2C::Error> {
-        self.i2c.write(ADDR, &[0x25, thr])
+        self.write_raw(&[0x25, thr])
     }
 
+    /// Read the OCP, OTP and derating thresholds (raw register values).
+    /// These are separate registers, see `read_each()`.
     pub fn read_thr(&mut self) -> Result<[u8; 3], I2C::Error> {
-        // unclear why read_buf does not work here
-        let mut buf: [u8; 3] = [0, 0, 0];
-        self.i2c.write_read(ADDR, &[0x23], &mut buf[0..1])?;
-        self.i2c.write_read(ADDR, &[0x24], &mut buf[1..2])?;
-        self.i2c.write_read(ADDR, &[0x25], &mut buf[2..3])?;
-        Ok(buf)
+        self.read_each([0x23, 0x24, 0x25])
     }
 
-    pub fn write_tr(&mut self, tr: [u8; 8]) -> Result<(), I2C::Error> {
-        self.i2c.write(ADDR, &tr)
+    /// Read all registers known to the driver, e.g. to attach them to a bug
+    /// report. The status is read with `update()`, so that the new PDO flag
+    /// it clears is not lost to the driver, and the RDO register is
+    /// write-only.
+    pub fn dump_registers(&mut self) -> Result<RegisterDump, I2C::Error> {
+        self.update()?;
+        let pdos = self.read_buf(0x00)?;
+        let [npdos, irqmask, voltage, current, temp, ocpthr, otpthr, drthr] =
+            self.read_each([0x1c, 0x1e, 0x20, 0x21, 0x22, 0x23, 0x24, 0x25])?;
+        let mut tr = [0; 8];
+        for (reg, val) in (0x28..).step_by(2).zip(tr.chunks_mut(2)) {
+            self.read_register(reg, val)?;
+        }
+        Ok(RegisterDump {
+            pdos,
+            npdos,
+            status: self.status.0,
+            irqmask,
+            voltage,
+            current,
+            temp: self.state.tr_written.then_some(temp),
+            ocpthr,
+            otpthr,
+            drthr,
+            tr,
+        })
     }
 
-    pub fn write_rdo(&mut self, rdo: &RDO) -> Result<(), I2C::Error> {
+    /// Check that the device at the address is plausibly an AP33772. The
+    /// chip has no ID or revision register, so this can only verify that
+    /// it acknowledges and reports a valid number of PDOs (at most 7).
+    pub fn probe(&mut self) -> Result<(), Error<I2C::Error>> {
+        match self.read_npdos().map_err(Error::I2c)? {
+            0..=7 => Ok(()),
+            _ => Err(Error::UnexpectedDevice),
+        }
+    }
+
+    /// Apply the initial configuration, after checking the device with
+    /// `probe()`, then read the status with `update()` and the PDOs if the
+    /// chip is ready and they were not read along with it. The NTC table is
+    /// written first so that the temperature thresholds act on valid
+    /// readings, the interrupt mask last so that no events fire before the
+    /// thresholds are set.
+    ///
+    /// The first error aborts the sequence and is returned. The chip is then
+    /// only partially configured and callers must not proceed as if it was,
+    /// e.g. retry `init()` or keep the output disabled.
+    pub fn init(&mut self, cfg: &InitConfig) -> Result<(), Error<I2C::Error>> {
+        self.probe()?;
+        self.write_tr(cfg.tr)?;
+        self.write_ocpthr(cfg.config.ocp_ma)?;
+        self.write_otpthr(cfg.config.otp_c).map_err(Error::I2c)?;
+        self.write_drthr(cfg.config.dr_c).map_err(Error::I2c)?;
+        self.write_irqmask(cfg.config.irq_mask.0)
+            .map_err(Error::I2c)?;
+        self.update().map_err(Error::I2c)?;
+        if self.status.ready() && self.pdo_count() == 0 {
+            self.read_pdos()?;
+        }
+        Ok(())
+    }
+
+    pub fn read_config(&mut self) -> Result<DeviceConfig, I2C::Error> {
+        let irq_mask = IrqMask(self.read_irqmask()?);
+        let [ocp, otp, dr] = self.read_thr()?;
+        Ok(DeviceConfig {
+            irq_mask,
+            ocp_ma: ocp_raw_to_ma(ocp),
+            otp_c: otp,
+            dr_c: dr,
+        })
+    }
+
+    pub fn write_config(&mut self, cfg: &DeviceConfig) -> Result<(), Error<I2C::Error>> {
+        self.write_ocpthr(cfg.ocp_ma)?;
+        self.write_irqmask(cfg.irq_mask.0).map_err(Error::I2c)?;
+        self.write_otpthr(cfg.otp_c).map_err(Error::I2c)?;
+        self.write_drthr(cfg.dr_c).map_err(Error::I2c)
+    }
+
+    /// Write the NTC resistance table (TR25, TR50, TR75, TR100), e.g. as
+    /// computed by `ntc_table()`. Each entry is a separate 2-byte register.
+    /// The resistance of an NTC falls with temperature, so a table whose
+    /// entries are not strictly decreasing (the last one non-zero) is
+    /// rejected with `Error::ValueOutOfRange` without writing anything.
+    pub fn write_tr(&mut self, tr: [u8; 8]) -> Result<(), Error<I2C::Error>> {
+        let r = tr_resistances(tr);
+        if r[3] == 0 || r.windows(2).any(|w| w[0] <= w[1]) {
+            return Err(Error::ValueOutOfRange);
+        }
+        for (reg, val) in (0x28..).step_by(2).zip(tr.chunks(2)) {
+            self.write_register(reg, val).map_err(Error::I2c)?;
+        }
+        self.state.tr_written = true;
+        Ok(())
+    }
+
+    fn write_rdo_reg(&mut self, rdo: u32) -> Result<(), I2C::Error> {
         let mut buf = [0u8; 5];
         buf[0] = 0x30;
-        buf[1..5].copy_from_slice(&rdo.reg().to_le_bytes());
-        self.i2c.write(ADDR, &buf)
+        buf[1..5].copy_from_slice(&rdo.to_le_bytes());
+        self.write_raw(&buf)?;
+        self.state.request_pending = true;
+        Ok(())
+    }
+
+    pub fn write_rdo(&mut self, rdo: &RDO) -> Result<(), I2C::Error> {
+        self.write_rdo_reg(*rdo.reg())?;
+        self.state.rdo = *rdo.reg();
+        self.state.derated = false;
+        if let Some(pos) = rdo.position() {
+            self.observer.on_event(PdEvent::Requested(pos));
+        }
+        Ok(())
+    }
+
+    /// `write_rdo()` which first checks that the object position of `rdo`
+    /// refers to a PDO advertised by the source.
+    pub fn write_rdo_checked(&mut self, rdo: &RDO) -> Result<(), Error<I2C::Error>> {
+        match self.requested_pdo(*rdo.reg()) {
+            Some(_) => self.write_rdo(rdo).map_err(Error::I2c),
+            None => Err(Error::InvalidPdoIndex),
+        }
+    }
+
+    /// The advertised PDO at the object position of the raw `rdo`.
+    fn requested_pdo(&self, rdo: u32) -> Option<&PDO> {
+        let pos = PdoPosition::new(FixedRDO(rdo).position() as u8)?;
+        self.pdos.get(pos.index())?.as_ref()
+    }
+
+    /// Request the programmable PDO at `pdo_index` (index into `pdos`) with
+    /// the given voltage and current. Both are clamped to the range
+    /// advertised by the PDO; if the current exceeds it, the capability
+    /// mismatch flag is set.
+    pub fn request_pps(
+        &mut self,
+        pdo_index: usize,
+        voltage_mv: u16,
+        current_ma: u16,
+    ) -> Result<(), Error<I2C::Error>> {
+        let pdo = match self.pdos.get(pdo_index) {
+            Some(Some(pdo @ PDO::Programmable(_))) => pdo,
+            _ => return Err(Error::InvalidPdoIndex),
+        };
+        if pdo.vmax() == 0 || pdo.vmin() > pdo.vmax() {
+            return Err(Error::VoltageOutOfRange);
+        }
+        let v_set = (voltage_mv as u32).clamp(pdo.vmin(), pdo.vmax());
+        let i_set = (current_ma as u32).min(self.max_current_ma(pdo, v_set));
+        let mismatch = current_ma as u32 > pdo.imax();
+
+        let pos = PdoPosition::from_index(pdo_index).ok_or(Error::InvalidPdoIndex)?;
+        let mut ardo = ARDO::new(pos, v_set as u16, i_set as u16)?;
+        ardo.set_capability_mismatch(mismatch);
+        self.write_rdo(&RDO::ARDO(ardo)).map_err(Error::I2c)
+    }
+
+    /// Move the programmable PDO at `pdo_index` to `target_mv` in steps of
+    /// at most `step_mv`, waiting `step_delay_ms` after each request, for
+    /// loads that cannot tolerate large voltage jumps. The ramp starts at
+    /// the voltage of the last request if it was for the same PDO, else at
+    /// the measured VBUS voltage. The current of that request is kept,
+    /// otherwise the maximum current of the PDO is requested.
+    pub fn ramp_pps(
+        &mut self,
+        pdo_index: usize,
+        target_mv: u16,
+        step_mv: u16,
+        delay: &mut impl DelayNs,
+        step_delay_ms: u32,
+    ) -> Result<(), Error<I2C::Error>> {
+        let pdo = match self.pdos.get(pdo_index) {
+            Some(Some(pdo @ PDO::Programmable(_))) => *pdo,
+            _ => return Err(Error::InvalidPdoIndex),
+        };
+        let ardo = ARDO(self.state.rdo);
+        let (v, i) = if self.state.rdo != 0 && ardo.position() as usize == pdo_index + 1 {
+            (ardo.voltage_mv() as u16, ardo.current_ma() as u16)
+        } else {
+            let v = self.read_voltage().map_err(Error::I2c)?;
+            (v, pdo.imax() as u16)
+        };
+        let clamp = |v: u16| (v as u32).clamp(pdo.vmin(), pdo.vmax()) as u16;
+        let (mut v, target_mv) = (clamp(v), clamp(target_mv));
+        let step_mv = step_mv.max(ARDO_V_LSB_MV as u16);
+        while v != target_mv {
+            v = if v < target_mv {
+                v.saturating_add(step_mv).min(target_mv)
+            } else {
+                v.saturating_sub(step_mv).max(target_mv)
+            };
+            self.request_pps(pdo_index, v, i)?;
+            delay.delay_ms(step_delay_ms);
+        }
+        Ok(())
+    }
+
+    /// Request `target_mv` from the programmable PDO at `pdo_index` and
+    /// correct the requested voltage until the measured VBUS voltage under
+    /// the present load matches the target, e.g. to compensate the sag of
+    /// the source. After each request the source is given `PPS_SETTLE_MS`
+    /// to settle, then the voltage is moved by the remaining error in steps
+    /// of `ARDO_V_LSB_MV`, by at most `PPS_REGULATION_MAX_MV` from the
+    /// target and within the range of the PDO. Stops once the error is
+    /// within the measurement resolution (`VOLTAGE_LSB_MV` / 2), cannot be
+    /// corrected any further or after `PPS_REGULATION_STEPS` requests; the
+    /// last request stays in place. The current is kept as in `ramp_pps()`.
+    pub fn request_pps_regulated(
+        &mut self,
+        pdo_index: usize,
+        target_mv: u16,
+        delay: &mut impl DelayNs,
+    ) -> Result<(), Error<I2C::Error>> {
+        let pdo = match self.pdos.get(pdo_index) {
+            Some(Some(pdo @ PDO::Programmable(_))) => *pdo,
+            _ => return Err(Error::InvalidPdoIndex),
+        };
+        let ardo = ARDO(self.state.rdo);
+        let i = if self.state.rdo != 0 && ardo.position() as usize == pdo_index + 1 {
+            ardo.current_ma() as u16
+        } else {
+            pdo.imax() as u16
+        };
+        let target = (target_mv as u32).clamp(pdo.vmin(), pdo.vmax()) as i32;
+        let (lo, hi) = (
+            (target - PPS_REGULATION_MAX_MV as i32).max(pdo.vmin() as i32),
+            (target + PPS_REGULATION_MAX_MV as i32).min(pdo.vmax() as i32),
+        );
+        let step = ARDO_V_LSB_MV as i32;
+        let mut v_set = target;
+        for _ in 0..PPS_REGULATION_STEPS {
+            self.request_pps(pdo_index, v_set as u16, i)?;
+            delay.delay_ms(PPS_SETTLE_MS);
+            let err = target - self.read_voltage().map_err(Error::I2c)? as i32;
+            if err.abs() <= VOLTAGE_LSB_MV as i32 / 2 {
+                break;
+            }
+            let next = ((v_set + err + step / 2).div_euclid(step) * step).clamp(lo, hi);
+            if next == v_set {
+                break;
+            }
+            v_set = next;
+        }
+        Ok(())
+    }
+
+    /// Request the fixed PDO at `pdo_index` (index into `pdos`) with the
+    /// given operating current, clamped to the maximum advertised current.
+    /// If the current exceeds it, the capability mismatch flag is set.
+    pub fn request_fixed(
+        &mut self,
+        pdo_index: usize,
+        current_ma: u16,
+    ) -> Result<(), Error<I2C::Error>> {
+        let pdo = match self.pdos.get(pdo_index) {
+            Some(Some(pdo @ PDO::Fixed(_))) => pdo,
+            _ => return Err(Error::InvalidPdoIndex),
+        };
+        let i_set = (current_ma as u32).min(self.max_current_ma(pdo, pdo.vmax()));
+        let mismatch = current_ma as u32 > pdo.imax();
+
+        let pos = PdoPosition::from_index(pdo_index).ok_or(Error::InvalidPdoIndex)?;
+        let mut frdo = FixedRDO::new(pos, i_set as u16, i_set as u16)?;
+        frdo.set_capability_mismatch(mismatch);
+        self.write_rdo(&RDO::FixedRDO(frdo)).map_err(Error::I2c)
+    }
+
+    /// Request the vSafe5V fixed PDO, which every compliant source advertises
+    /// at position 1, at its maximum current, e.g. to keep the board
+    /// powered at a known voltage when the desired profile cannot be
+    /// negotiated. `Error::NoCompatiblePdo` if the first PDO is not 5 V.
+    pub fn ensure_safe_default(&mut self) -> Result<(), Error<I2C::Error>> {
+        match self.pdos[0] {
+            Some(pdo @ PDO::Fixed(_)) if pdo.vmax() == 5000 => {
+                self.request_fixed(0, pdo.imax() as u16)
+            }
+            _ => Err(Error::NoCompatiblePdo),
+        }
+    }
+
+    /// Re-request the PDO of the last request at `current_ma`, keeping the
+    /// voltage of a programmable request, e.g. to follow a dynamic load
+    /// without selecting a PDO again. The current is clamped as for
+    /// `request_fixed()` and `request_pps()`. `Error::InvalidPdoIndex` if
+    /// no request has been made since the source attached.
+    pub fn adjust_current(&mut self, current_ma: u16) -> Result<(), Error<I2C::Error>> {
+        let pos = PdoPosition::new(FixedRDO(self.state.rdo).position() as u8);
+        match (pos, self.requested_pdo(self.state.rdo)) {
+            (Some(pos), Some(PDO::Fixed(_))) => self.request_fixed(pos.index(), current_ma),
+            (Some(pos), Some(PDO::Programmable(_))) => {
+                let voltage_mv = ARDO(self.state.rdo).voltage_mv() as u16;
+                self.request_pps(pos.index(), voltage_mv, current_ma)
+            }
+            _ => Err(Error::InvalidPdoIndex),
+        }
+    }
+
+    /// Re-send the last requested RDO, e.g. after new PDOs were advertised
+    /// by the source. Returns `false` if no request has been made yet.
+    pub fn renegotiate(&mut self) -> Result<bool, I2C::Error> {
+        if self.state.rdo == 0 {
+            return Ok(false);
+        }
+        self.write_rdo_reg(self.state.rdo)?;
+        self.state.derated = false;
+        Ok(true)
+    }
+
+    /// Set the fraction (in percent) of the requested current to fall back
+    /// to while the chip signals thermal derating, defaults to 70 %.
+    pub fn set_derating_percent(&mut self, percent: u8) {
+        self.state.derating_percent = percent.min(100);
+    }
+
+    /// Whether the chip signalled thermal derating in the last status. The
+    /// AP33772 only exposes this flag, see `derating_info()` for how close
+    /// the temperature is to the threshold.
+    pub fn should_reduce_current(&self) -> bool {
+        self.status.derating()
+    }
+
+    /// Compare the temperature with the derating threshold, `None` if it is
+    /// more than `DERATING_MARGIN_C` below. `Error::NtcNotConfigured`
+    /// before `write_tr()`, see `read_temp()`.
+    pub fn derating_info(&mut self) -> Result<Option<DeratingLevel>, Error<I2C::Error>> {
+        if !self.state.tr_written {
+            return Err(Error::NtcNotConfigured);
+        }
+        let [temp, dr] = self.read_each([0x22, 0x25]).map_err(Error::I2c)?;
+        Ok(if self.status.derating() || temp >= dr {
+            Some(DeratingLevel::Active)
+        } else if temp.saturating_add(DERATING_MARGIN_C) >= dr {
+            Some(DeratingLevel::Approaching)
+        } else {
+            None
+        })
     }
 
+    /// Distance (°C) of the temperature below the OTP threshold, negative
+    /// above it, e.g. to reduce the load before OTP switches the output off.
+    /// `Error::NtcNotConfigured` before `write_tr()`, see `read_temp()`.
+    pub fn temp_margin_c(&mut self) -> Result<i16, Error<I2C::Error>> {
+        if !self.state.tr_written {
+            return Err(Error::NtcNotConfigured);
+        }
+        let [temp, otp] = self.read_each([0x22, 0x24]).map_err(Error::I2c)?;
+        Ok(otp as i16 - temp as i16)
+    }
+
+    /// React to the derating status bit: while it is set, the last request
+    /// is re-issued for the same PDO at a reduced current; once it clears,
+    /// the original request is restored. Returns whether an RDO was written.
+    pub fn handle_derating(&mut self) -> Result<bool, I2C::Error> {
+        if self.state.rdo == 0 || self.should_reduce_current() == self.state.derated {
+            return Ok(false);
+        }
+        if self.state.derated {
+            return self.renegotiate();
+        }
+        let Some(rdo) = self.derated_rdo() else {
+            return Ok(false);
+        };
+        self.write_rdo_reg(rdo)?;
+        self.state.derated = true;
+        Ok(true)
+    }
+
+    /// The last request with the current reduced to `derating_percent`, as
+    /// written by `handle_derating()`.
+    fn derated_rdo(&self) -> Option<u32> {
+        let percent = self.state.derating_percent as u32;
+        match self.requested_pdo(self.state.rdo)? {
+            PDO::Fixed(_) => {
+                let mut frdo = FixedRDO(self.state.rdo);
+                let i_set = frdo.current() * percent / 100;
+                frdo.i(i_set);
+                frdo.imax(i_set);
+                Some(frdo.0)
+            }
+            PDO::Programmable(_) => {
+                let mut ardo = ARDO(self.state.rdo);
+                let i_set = ardo.current() * percent / 100;
+                ardo.i(i_set);
+                Some(ardo.0)
+            }
+        }
+    }
+
+    /// Write an all-zero RDO, which makes the AP33772 issue a USB-PD Hard
+    /// Reset to the source. VBUS drops to 0 V and returns to 5 V, after
+    /// which the source advertises its capabilities again (`newpdos`). The
+    /// AP33772 itself has no reset register, its state machine restarts
+    /// with the PD link. The implicit 5 V contract after the reset is not a
+    /// request of the driver, so it does not enable the output.
+    pub fn hard_reset(&mut self) -> Result<(), I2C::Error> {
+        self.write_rdo_reg(0)?;
+        self.state.rdo = 0;
+        self.state.derated = false;
+        self.state.request_pending = false;
+        self.state.contract_established = false;
+        Ok(())
+    }
+
+    #[deprecated(note = "sends a PD Hard Reset, use `hard_reset` instead")]
     pub fn reset(&mut self) -> Result<(), I2C::Error> {
-        let buf = [0x30, 0, 0, 0, 0];
-        self.i2c.write(ADDR, &buf)
+        self.hard_reset()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn telemetry_frame() {
+        let t = Telemetry {
+            voltage_mv: 9040,
+            current_ma: 1488,
+            temp: Some(31),
+            status: Status(0x03),
+        };
+        assert_eq!(t.to_frame(7), [0xa5, 7, 0x50, 0x23, 0xd0, 0x05, 31, 0x03]);
+    }
+
+    #[test]
+    fn better_than() {
+        let fixed_5v = PDO::decode(0x0001_912c).unwrap();
+        let fixed_9v = PDO::decode(0x0002_d12c).unwrap();
+        let pps = PDO::decode(0xc0dc_213c).unwrap();
+        let closest = SelectionPolicy::ClosestVoltage;
+        assert!(pps.better_than(&fixed_9v, SelectionPolicy::PreferProgrammable, 0));
+        assert!(!fixed_9v.better_than(&pps, SelectionPolicy::PreferProgrammable, 0));
+        assert!(fixed_9v.better_than(&fixed_5v, SelectionPolicy::MaxPower, 0));
+        assert!(!fixed_9v.better_than(&fixed_5v, SelectionPolicy::MaxCurrent, 0));
+        assert!(fixed_5v.better_than(&fixed_9v, closest, 6000));
+        assert!(pps.better_than(&fixed_9v, closest, 7000));
+    }
+
+    #[test]
+    fn negotiation_state() {
+        use NegotiationState::*;
+        let mut state = NegotiationState::default();
+        let steps = [
+            (0x00, Idle),
+            (0x05, ReadingPdos),
+            (0x01, Requested),
+            (0x03, Active),
+            (0x13, Faulted),
+            (0x03, Active),
+            (0x07, ReadingPdos),
+            (0x00, Idle),
+            // attached while the new PDO flag was missed
+            (0x01, ReadingPdos),
+        ];
+        for (status, expected) in steps {
+            assert_eq!(state.step(Status(status)), expected);
+        }
+    }
+
+    #[test]
+    fn ntc_table_beta() {
+        // 10k, B = 3900: the Beta equation evaluated with a libm exp() gives
+        // 10000, 3635.04, 1528.04 and 721.43 ohms
+        assert_eq!(
+            ntc_table(10_000, 3900).map(tr_resistances),
+            Some([10000, 3635, 1528, 721])
+        );
+    }
+
+    #[test]
+    fn ntc_table_range() {
+        assert!(ntc_table(47_000, 4050).is_some());
+        assert_eq!(
+            ntc_table(65_535, 3435).map(|tr| tr_resistances(tr)[0]),
+            Some(65_535)
+        );
+        assert_eq!(ntc_table(65_536, 3435), None);
+        assert_eq!(ntc_table(100_000, 4250), None);
+    }
+
+    #[test]
+    fn ntc_table_default() {
+        // the reset values of TR25..TR100 in the AP33772 datasheet (10000,
+        // 4161, 1928 and 974 ohms for a 10k NTC) are matched within 2 % by
+        // the Beta equation with B = 3435
+        let default = [0x10, 0x27, 0x41, 0x10, 0x88, 0x07, 0xce, 0x03];
+        let computed = tr_resistances(ntc_table(10_000, 3435).unwrap());
+        for (r, r_def) in computed.iter().zip(tr_resistances(default)) {
+            assert!(
+                r.abs_diff(r_def) as u32 * 50 <= r_def as u32,
+                "{r} vs. {r_def}"
+            );
+        }
     }
 }
@@ -1,6 +1,14 @@
 use bitfield::bitfield;
 
+use super::scale::*;
+
 bitfield! {
+    /// Status register. `ready` and `success` reflect the state of the
+    /// link for as long as it lasts. `newpdos` is set once per
+    /// advertisement and cleared by reading the register, the protection
+    /// flags stay set while the condition persists.
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
     pub struct Status(u8);
     impl Debug;
     pub derating, _: 7;
@@ -12,7 +20,15 @@ bitfield! {
     pub ready, _: 0;
 }
 
+impl Status {
+    /// Whether OVP, OCP or OTP is set, any of which switches the output off.
+    pub fn fault(&self) -> bool {
+        self.ovp() || self.ocp() || self.otp()
+    }
+}
+
 bitfield! {
+    #[derive(Clone, Copy, PartialEq, Eq)]
     pub struct IrqMask(u8);
     impl Debug;
     pub derating, enable_derating: 7;
@@ -24,48 +40,178 @@ bitfield! {
     pub ready, enable_ready: 0;
 }
 
+/// Events that can be routed to the interrupt pin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqEvent {
+    Ovp,
+    Ocp,
+    Otp,
+    Derating,
+    NewPdo,
+    Success,
+    Ready,
+}
+
+impl IrqMask {
+    /// Mask enabling exactly the given events.
+    pub fn for_events(events: &[IrqEvent]) -> Self {
+        let mut mask = IrqMask(0);
+        for event in events {
+            match event {
+                IrqEvent::Ovp => mask.enable_ovp(true),
+                IrqEvent::Ocp => mask.enable_ocp(true),
+                IrqEvent::Otp => mask.enable_otp(true),
+                IrqEvent::Derating => mask.enable_derating(true),
+                IrqEvent::NewPdo => mask.enable_newpdo(true),
+                IrqEvent::Success => mask.enable_success(true),
+                IrqEvent::Ready => mask.enable_ready(true),
+            }
+        }
+        mask
+    }
+
+    /// Mask enabling all events.
+    pub fn all() -> Self {
+        Self::for_events(&[
+            IrqEvent::Ovp,
+            IrqEvent::Ocp,
+            IrqEvent::Otp,
+            IrqEvent::Derating,
+            IrqEvent::NewPdo,
+            IrqEvent::Success,
+            IrqEvent::Ready,
+        ])
+    }
+
+    /// Mask enabling only the protection events.
+    pub fn faults_only() -> Self {
+        Self::for_events(&[
+            IrqEvent::Ovp,
+            IrqEvent::Ocp,
+            IrqEvent::Otp,
+            IrqEvent::Derating,
+        ])
+    }
+}
+
 bitfield! {
+    #[derive(Clone, Copy, PartialEq, Eq)]
     pub struct FixedPDO(u32);
     impl Debug;
-    pub v, _: 19, 10; // LSB 50 mV
-    pub imax, _: 9, 0; // LSB 10 mA
+    pub dual_role_power, _: 29;
+    pub usb_suspend_supported, _: 28;
+    pub unconstrained_power, _: 27;
+    pub usb_comms_capable, _: 26;
+    pub dual_role_data, _: 25;
+    pub unchunked_ext_messages, _: 24;
+    pub epr_mode_capable, _: 23;
+    pub peak_current, _: 21, 20;
+    pub v, _: 19, 10; // FIXED_PDO_V_LSB_MV
+    pub imax, _: 9, 0; // FIXED_PDO_I_LSB_MA
 }
 
 bitfield! {
+    /// SPR programmable power supply (PPS) APDO. Besides the voltage range
+    /// only the maximum current is encoded: PPS has no minimum current,
+    /// the sink may draw anything up to `imax` over the whole range, less
+    /// if `pps_power_limited` is set and the source's power rating is
+    /// reached. Bits 26..25, 16 and 7 are reserved, see `reserved()`.
+    #[derive(Clone, Copy, PartialEq, Eq)]
     pub struct APDO(u32);
     impl Debug;
-    pub vmax, _: 24, 17; // LSB 100 mV
-    pub vmin, _: 15, 8; // LSB 100 mV
-    pub imax, _: 6, 0; // LSB 50 mA
+    /// 0b00 for SPR PPS
+    pub apdo_type, _: 29, 28;
+    pub pps_power_limited, _: 27;
+    pub vmax, _: 24, 17; // APDO_V_LSB_MV
+    pub vmin, _: 15, 8; // APDO_V_LSB_MV
+    pub imax, _: 6, 0; // APDO_I_LSB_MA
 }
 
+impl APDO {
+    /// Reserved bits, which a spec-compliant source leaves 0.
+    pub fn reserved(&self) -> u32 {
+        self.0 & 0x0601_0080
+    }
+
+    /// Voltages (mV) that can be requested from this APDO, from `vmin` to
+    /// `vmax` in steps of the RDO resolution `ARDO_V_LSB_MV`.
+    pub fn voltage_steps(&self) -> impl Iterator<Item = u16> {
+        (apdo_mv(self.vmin())..=apdo_mv(self.vmax()))
+            .step_by(ARDO_V_LSB_MV as usize)
+            .map(|v| v as u16)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PDO {
     Fixed(FixedPDO),
     Programmable(APDO),
 }
 
 impl PDO {
+    /// Decode a raw PDO as read from the chip, `None` for empty slots and
+    /// PDO types other than fixed supply and PPS.
+    pub fn decode(raw: u32) -> Option<Self> {
+        if raw == 0x0 {
+            None
+        } else if raw & 0xf000_0000 == 0xc000_0000 {
+            Some(PDO::Programmable(APDO(raw)))
+        } else if raw & 0xc000_0000 == 0x0 {
+            Some(PDO::Fixed(FixedPDO(raw)))
+        } else {
+            None
+        }
+    }
+
     pub fn vmin(&self) -> u32 {
         match self {
-            PDO::Fixed(pdo) => pdo.v() * 50,
-            PDO::Programmable(pdo) => pdo.vmin() * 100,
+            PDO::Fixed(pdo) => fixed_pdo_mv(pdo.v()),
+            PDO::Programmable(pdo) => apdo_mv(pdo.vmin()),
         }
     }
 
     pub fn vmax(&self) -> u32 {
         match self {
-            PDO::Fixed(pdo) => pdo.v() * 50,
-            PDO::Programmable(pdo) => pdo.vmax() * 100,
+            PDO::Fixed(pdo) => fixed_pdo_mv(pdo.v()),
+            PDO::Programmable(pdo) => apdo_mv(pdo.vmax()),
         }
     }
 
     pub fn imax(&self) -> u32 {
         match self {
-            PDO::Fixed(pdo) => pdo.imax() * 10,
-            PDO::Programmable(pdo) => pdo.imax() * 50,
+            PDO::Fixed(pdo) => fixed_pdo_ma(pdo.imax()),
+            PDO::Programmable(pdo) => apdo_ma(pdo.imax()),
         }
     }
 
+    /// Nominal voltage in mV, the midpoint of the range for programmable PDOs.
+    pub fn nominal_mv(&self) -> u32 {
+        (self.vmin() + self.vmax()) / 2
+    }
+
+    /// Maximum power in mW at the highest voltage.
+    pub fn max_power_mw(&self) -> u32 {
+        self.vmax() * self.imax() / 1000
+    }
+
+    /// Write a compact JSON record with `index` as `i`, e.g.
+    /// `{"i":1,"t":"fixed","vmin":5000,"vmax":5000,"imax":3000}`.
+    pub fn write_record(&self, w: &mut impl core::fmt::Write, index: usize) -> core::fmt::Result {
+        let t = match self {
+            PDO::Fixed(_) => "fixed",
+            PDO::Programmable(_) => "pps",
+        };
+        write!(
+            w,
+            "{{\"i\":{},\"t\":\"{}\",\"vmin\":{},\"vmax\":{},\"imax\":{}}}",
+            index,
+            t,
+            self.vmin(),
+            self.vmax(),
+            self.imax()
+        )
+    }
+
     pub fn vcomp(&self, vmin: u32, vmax: u32) -> bool {
         (vmin <= self.vmax()) && (self.vmin() <= vmax)
     }
@@ -75,22 +221,136 @@ impl PDO {
     }
 }
 
+impl core::fmt::Display for PDO {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            PDO::Fixed(_) => write!(f, "Fixed {}mV {}mA", self.vmax(), self.imax()),
+            PDO::Programmable(_) => {
+                write!(f, "PPS {}-{}mV {}mA", self.vmin(), self.vmax(), self.imax())
+            }
+        }
+    }
+}
+
 bitfield! {
+    #[derive(Clone, Copy, PartialEq, Eq)]
     pub struct FixedRDO(u32);
     impl Debug;
-    pub _, pos: 30, 28;
-    pub _, i: 19, 10; // LSB 10 mA
-    pub _, imax: 9, 0; // LSB 10 mA
+    pub position, pos: 30, 28;
+    pub giveback, set_giveback: 27;
+    pub capability_mismatch, set_capability_mismatch: 26;
+    pub usb_comms_capable, set_usb_comms_capable: 25;
+    pub no_usb_suspend, set_no_usb_suspend: 24;
+    pub current, i: 19, 10; // FIXED_RDO_I_LSB_MA
+    pub max_current, imax: 9, 0; // FIXED_RDO_I_LSB_MA
 }
 
 bitfield! {
+    #[derive(Clone, Copy, PartialEq, Eq)]
     pub struct ARDO(u32);
     impl Debug;
-    pub _, pos: 30, 28;
-    pub _, volt: 19, 9; // LSB 20 mV
-    pub _, i: 6, 0; // LSB 50 mA
+    pub position, pos: 30, 28;
+    pub capability_mismatch, set_capability_mismatch: 26;
+    pub usb_comms_capable, set_usb_comms_capable: 25;
+    pub no_usb_suspend, set_no_usb_suspend: 24;
+    pub voltage, volt: 19, 9; // ARDO_V_LSB_MV
+    pub current, i: 6, 0; // ARDO_I_LSB_MA
+}
+
+/// Object position of a PDO as referenced by an RDO, always in 1..=7.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PdoPosition(u8);
+
+impl PdoPosition {
+    pub const fn new(pos: u8) -> Option<Self> {
+        match pos {
+            1..=7 => Some(PdoPosition(pos)),
+            _ => None,
+        }
+    }
+
+    /// Position of the PDO at `index` into the 0-based PDO array.
+    pub fn from_index(index: usize) -> Option<Self> {
+        Self::new(u8::try_from(index.checked_add(1)?).ok()?)
+    }
+
+    pub const fn get(self) -> u8 {
+        self.0
+    }
+
+    /// 0-based index into the PDO array.
+    pub const fn index(self) -> usize {
+        self.0 as usize - 1
+    }
+}
+
+/// Reason why an RDO cannot be constructed from the given values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProfileError {
+    VoltageOutOfRange,
+    CurrentOutOfRange,
+}
+
+impl FixedRDO {
+    /// Construct an RDO for the fixed PDO at object position `pos` with
+    /// operating and maximum current in mA, checking that all values fit
+    /// their fields.
+    pub fn new(
+        pos: PdoPosition,
+        current_ma: u16,
+        max_current_ma: u16,
+    ) -> Result<Self, ProfileError> {
+        let i = current_ma as u32 / FIXED_RDO_I_LSB_MA;
+        let imax = max_current_ma as u32 / FIXED_RDO_I_LSB_MA;
+        if i > 0x3ff || imax > 0x3ff {
+            return Err(ProfileError::CurrentOutOfRange);
+        }
+        let mut rdo = FixedRDO(0);
+        rdo.pos(pos.get() as u32);
+        rdo.i(i);
+        rdo.imax(imax);
+        Ok(rdo)
+    }
+
+    /// Operating current in mA.
+    pub fn current_ma(&self) -> u32 {
+        self.current() * FIXED_RDO_I_LSB_MA
+    }
 }
 
+impl ARDO {
+    /// Construct an RDO for the programmable PDO at object position `pos`
+    /// with output voltage in mV and operating current in mA, checking that
+    /// all values fit their fields.
+    pub fn new(pos: PdoPosition, voltage_mv: u16, current_ma: u16) -> Result<Self, ProfileError> {
+        let volt = voltage_mv as u32 / ARDO_V_LSB_MV;
+        let i = current_ma as u32 / ARDO_I_LSB_MA;
+        if volt > 0x7ff {
+            return Err(ProfileError::VoltageOutOfRange);
+        }
+        if i > 0x7f {
+            return Err(ProfileError::CurrentOutOfRange);
+        }
+        let mut rdo = ARDO(0);
+        rdo.pos(pos.get() as u32);
+        rdo.volt(volt);
+        rdo.i(i);
+        Ok(rdo)
+    }
+
+    /// Output voltage in mV.
+    pub fn voltage_mv(&self) -> u32 {
+        self.voltage() * ARDO_V_LSB_MV
+    }
+
+    /// Operating current in mA.
+    pub fn current_ma(&self) -> u32 {
+        self.current() * ARDO_I_LSB_MA
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RDO {
     FixedRDO(FixedRDO),
     ARDO(ARDO),
@@ -103,4 +363,262 @@ impl RDO {
             RDO::ARDO(v) => &v.0,
         }
     }
+
+    /// Object position of the requested PDO, `None` if the field is 0.
+    pub fn position(&self) -> Option<PdoPosition> {
+        PdoPosition::new(FixedRDO(*self.reg()).position() as u8)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Status {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let mut s = serializer.serialize_struct("Status", 7)?;
+        s.serialize_field("ready", &self.ready())?;
+        s.serialize_field("success", &self.success())?;
+        s.serialize_field("newpdos", &self.newpdos())?;
+        s.serialize_field("ovp", &self.ovp())?;
+        s.serialize_field("ocp", &self.ocp())?;
+        s.serialize_field("otp", &self.otp())?;
+        s.serialize_field("derating", &self.derating())?;
+        s.end()
+    }
+}
+
+/// Flattened to `{"type": "fixed", "v_mv", "imax_ma"}` or
+/// `{"type": "pps", "vmin_mv", "vmax_mv", "imax_ma"}`.
+#[cfg(feature = "serde")]
+impl serde::Serialize for PDO {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        match self {
+            PDO::Fixed(_) => {
+                let mut s = serializer.serialize_struct("PDO", 3)?;
+                s.serialize_field("type", "fixed")?;
+                s.serialize_field("v_mv", &self.vmax())?;
+                s.serialize_field("imax_ma", &self.imax())?;
+                s.end()
+            }
+            PDO::Programmable(_) => {
+                let mut s = serializer.serialize_struct("PDO", 4)?;
+                s.serialize_field("type", "pps")?;
+                s.serialize_field("vmin_mv", &self.vmin())?;
+                s.serialize_field("vmax_mv", &self.vmax())?;
+                s.serialize_field("imax_ma", &self.imax())?;
+                s.end()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ardo_encode() {
+        // PDO 2, 9000 mV, 2000 mA
+        let mut ardo = ARDO(0);
+        ardo.pos(2);
+        ardo.volt(9000 / 20);
+        ardo.i(2000 / 50);
+        assert_eq!(ardo.0, 0x2003_8428);
+        assert_eq!(*RDO::ARDO(ardo).reg(), 0x2003_8428);
+    }
+
+    #[test]
+    fn ardo_decode() {
+        let mut ardo = ARDO(0);
+        ardo.pos(7);
+        ardo.volt(3300 / 20);
+        ardo.i(5000 / 50);
+        assert_eq!((ardo.0 >> 28) & 0x7, 7);
+        assert_eq!(((ardo.0 >> 9) & 0x7ff) * 20, 3300);
+        assert_eq!((ardo.0 & 0x7f) * 50, 5000);
+    }
+
+    #[test]
+    fn fixed_rdo_encode() {
+        // PDO 1, 3000 mA operating and max current
+        let mut frdo = FixedRDO(0);
+        frdo.pos(1);
+        frdo.i(3000 / 10);
+        frdo.imax(3000 / 10);
+        assert_eq!(frdo.0, 0x1004_b12c);
+        assert_eq!(*RDO::FixedRDO(frdo).reg(), 0x1004_b12c);
+    }
+
+    #[test]
+    fn fixed_rdo_decode() {
+        let mut frdo = FixedRDO(0);
+        frdo.pos(4);
+        frdo.i(1500 / 10);
+        frdo.imax(2000 / 10);
+        assert_eq!((frdo.0 >> 28) & 0x7, 4);
+        assert_eq!(((frdo.0 >> 10) & 0x3ff) * 10, 1500);
+        assert_eq!((frdo.0 & 0x3ff) * 10, 2000);
+    }
+
+    #[test]
+    fn rdo_pos_setter_truncates() {
+        // the raw setter truncates to the 3-bit field without leaking into
+        // the neighbouring bits, so 8 becomes 0 and 0xf becomes 7; out of
+        // range positions are rejected by `PdoPosition`, see `pdo_position`
+        let mut ardo = ARDO(0);
+        ardo.pos(8);
+        assert_eq!(ardo.0, 0);
+        ardo.pos(0xf);
+        assert_eq!(ardo.0, 0x7000_0000);
+
+        let mut frdo = FixedRDO(0);
+        frdo.pos(8);
+        assert_eq!(frdo.0, 0);
+        frdo.pos(0xf);
+        assert_eq!(frdo.0, 0x7000_0000);
+    }
+
+    #[test]
+    fn rdo_new() {
+        let pos = |n| PdoPosition::new(n).unwrap();
+        assert_eq!(ARDO::new(pos(2), 9000, 2000).unwrap().0, 0x2003_8428);
+        assert_eq!(FixedRDO::new(pos(1), 3000, 3000).unwrap().0, 0x1004_b12c);
+    }
+
+    #[test]
+    fn pdo_position() {
+        assert_eq!(PdoPosition::new(0), None);
+        assert_eq!(PdoPosition::new(8), None);
+        assert_eq!(PdoPosition::new(7).map(PdoPosition::index), Some(6));
+        assert_eq!(PdoPosition::from_index(0), PdoPosition::new(1));
+        assert_eq!(PdoPosition::from_index(7), None);
+        let rdo = RDO::ARDO(ARDO(0x2003_8428));
+        assert_eq!(rdo.position(), PdoPosition::new(2));
+        assert_eq!(RDO::FixedRDO(FixedRDO(0)).position(), None);
+    }
+
+    #[test]
+    fn rdo_new_out_of_range() {
+        let pos = PdoPosition::new(1).unwrap();
+        assert_eq!(
+            ARDO::new(pos, 41000, 2000),
+            Err(ProfileError::VoltageOutOfRange)
+        );
+        assert_eq!(
+            ARDO::new(pos, 9000, 6400),
+            Err(ProfileError::CurrentOutOfRange)
+        );
+        assert_eq!(
+            FixedRDO::new(pos, 10240, 3000),
+            Err(ProfileError::CurrentOutOfRange)
+        );
+        assert_eq!(
+            FixedRDO::new(pos, 3000, 10240),
+            Err(ProfileError::CurrentOutOfRange)
+        );
+    }
+
+    #[test]
+    fn pdo_decode() {
+        // 5 V, 3 A fixed
+        let pdo = PDO::Fixed(FixedPDO(0x0001_912c));
+        assert_eq!((pdo.vmin(), pdo.vmax(), pdo.imax()), (5000, 5000, 3000));
+        assert_eq!((pdo.nominal_mv(), pdo.max_power_mw()), (5000, 15000));
+
+        // 3.3 - 11 V, 3 A programmable
+        let pdo = PDO::Programmable(APDO(0xc0dc_213c));
+        assert_eq!((pdo.vmin(), pdo.vmax(), pdo.imax()), (3300, 11000, 3000));
+        assert_eq!((pdo.nominal_mv(), pdo.max_power_mw()), (7150, 33000));
+    }
+
+    #[test]
+    fn pdo_decode_raw() {
+        assert_eq!(PDO::decode(0), None);
+        assert!(matches!(PDO::decode(0x0001_912c), Some(PDO::Fixed(_))));
+        assert!(matches!(
+            PDO::decode(0xc0dc_213c),
+            Some(PDO::Programmable(_))
+        ));
+        // battery and variable supply PDOs are not supported
+        assert_eq!(PDO::decode(0x4001_912c), None);
+        assert_eq!(PDO::decode(0x8001_912c), None);
+    }
+
+    #[test]
+    fn apdo_fields() {
+        let apdo = APDO(0xc0dc_213c);
+        assert_eq!(apdo.apdo_type(), 0);
+        assert!(!apdo.pps_power_limited());
+        assert_eq!(apdo.reserved(), 0);
+        let apdo = APDO(0xc8dd_21bc);
+        assert!(apdo.pps_power_limited());
+        assert_eq!(apdo.reserved(), 0x0001_0080);
+    }
+
+    #[test]
+    fn pdo_record() {
+        extern crate std;
+        use std::string::String;
+
+        let mut rec = String::new();
+        PDO::Fixed(FixedPDO(0x0001_912c))
+            .write_record(&mut rec, 1)
+            .unwrap();
+        assert_eq!(
+            rec,
+            r#"{"i":1,"t":"fixed","vmin":5000,"vmax":5000,"imax":3000}"#
+        );
+        rec.clear();
+        PDO::Programmable(APDO(0xc0dc_213c))
+            .write_record(&mut rec, 2)
+            .unwrap();
+        assert_eq!(
+            rec,
+            r#"{"i":2,"t":"pps","vmin":3300,"vmax":11000,"imax":3000}"#
+        );
+    }
+
+    #[test]
+    fn irqmask_presets() {
+        assert_eq!(IrqMask::all().0, 0xf7);
+        assert_eq!(IrqMask::faults_only().0, 0xf0);
+        let mask = IrqMask::for_events(&[IrqEvent::NewPdo, IrqEvent::Success]);
+        assert_eq!(mask.0, 0x06);
+    }
+
+    #[test]
+    fn rdo_flags() {
+        let mut frdo = FixedRDO(0x1004_b12c);
+        frdo.set_capability_mismatch(true);
+        frdo.set_no_usb_suspend(true);
+        assert_eq!(frdo.0, 0x1504_b12c);
+        let mut ardo = ARDO(0x2003_8428);
+        ardo.set_usb_comms_capable(true);
+        assert_eq!(ardo.0, 0x2203_8428);
+        assert!(ardo.usb_comms_capable() && !ardo.capability_mismatch());
+    }
+
+    #[test]
+    fn voltage_steps() {
+        // 3.3 - 11 V
+        let apdo = APDO(0xc0dc_213c);
+        let mut steps = apdo.voltage_steps();
+        assert_eq!(steps.next(), Some(3300));
+        assert_eq!(steps.next(), Some(3320));
+        assert_eq!(steps.last(), Some(11000));
+        assert_eq!(apdo.voltage_steps().count(), 386);
+    }
+
+    #[test]
+    fn pdo_display() {
+        extern crate std;
+        use std::string::ToString;
+
+        let pdo = PDO::Fixed(FixedPDO(0x0001_912c));
+        assert_eq!(pdo.to_string(), "Fixed 5000mV 3000mA");
+        let pdo = PDO::Programmable(APDO(0xc0dc_213c));
+        assert_eq!(pdo.to_string(), "PPS 3300-11000mV 3000mA");
+    }
 }
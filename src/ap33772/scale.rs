@@ -0,0 +1,74 @@
+//! Scaling factors (LSB values) of the PDO, RDO and measurement fields, and
+//! conversions of raw values, e.g. from a bus capture.
+
+/// Fixed supply PDO voltage, mV
+pub const FIXED_PDO_V_LSB_MV: u32 = 50;
+/// Fixed supply PDO maximum current, mA
+pub const FIXED_PDO_I_LSB_MA: u32 = 10;
+/// Programmable (PPS) PDO minimum and maximum voltage, mV
+pub const APDO_V_LSB_MV: u32 = 100;
+/// Programmable (PPS) PDO maximum current, mA
+pub const APDO_I_LSB_MA: u32 = 50;
+
+/// Fixed supply RDO operating and maximum current, mA
+pub const FIXED_RDO_I_LSB_MA: u32 = 10;
+/// Programmable RDO output voltage, mV
+pub const ARDO_V_LSB_MV: u32 = 20;
+/// Programmable RDO operating current, mA
+pub const ARDO_I_LSB_MA: u32 = 50;
+
+/// VBUS voltage measurement, mV
+pub const VOLTAGE_LSB_MV: u16 = 80;
+/// VBUS current measurement, mA
+pub const CURRENT_LSB_MA: u16 = 24;
+/// Over-current protection threshold, mA
+pub const OCP_LSB_MA: u16 = 50;
+
+/// Convert a raw VBUS voltage reading (register 0x20) to mV.
+pub const fn raw_to_mv(raw: u8) -> u16 {
+    raw as u16 * VOLTAGE_LSB_MV
+}
+
+/// Convert a raw VBUS current reading (register 0x21) to mA.
+pub const fn raw_to_ma(raw: u8) -> u16 {
+    raw as u16 * CURRENT_LSB_MA
+}
+
+/// Convert a raw over-current threshold (register 0x23) to mA.
+pub const fn ocp_raw_to_ma(raw: u8) -> u16 {
+    raw as u16 * OCP_LSB_MA
+}
+
+/// Convert the voltage field of a fixed supply PDO to mV.
+pub const fn fixed_pdo_mv(field: u32) -> u32 {
+    field * FIXED_PDO_V_LSB_MV
+}
+
+/// Convert the maximum current field of a fixed supply PDO to mA.
+pub const fn fixed_pdo_ma(field: u32) -> u32 {
+    field * FIXED_PDO_I_LSB_MA
+}
+
+/// Convert a voltage field of a programmable PDO to mV.
+pub const fn apdo_mv(field: u32) -> u32 {
+    field * APDO_V_LSB_MV
+}
+
+/// Convert the maximum current field of a programmable PDO to mA.
+pub const fn apdo_ma(field: u32) -> u32 {
+    field * APDO_I_LSB_MA
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conversions() {
+        assert_eq!(raw_to_mv(255), 20400);
+        assert_eq!(raw_to_ma(255), 6120);
+        assert_eq!(ocp_raw_to_ma(2), 100);
+        assert_eq!((fixed_pdo_mv(100), fixed_pdo_ma(300)), (5000, 3000));
+        assert_eq!((apdo_mv(110), apdo_ma(60)), (11000, 3000));
+    }
+}
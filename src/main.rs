@@ -2,130 +2,132 @@
 #![no_main]
 
 use core::cell::RefCell;
-use core::cmp;
+use core::fmt::{self, Write};
+use core::future;
 use defmt::*;
 use {defmt_rtt as _, panic_probe as _};
 
-use embedded_hal_bus::i2c as bus_i2c;
+use embedded_hal::digital::OutputPin;
+use embedded_hal::i2c::I2c;
 
 use embassy_executor::Spawner;
 use embassy_futures::join;
+use embassy_futures::select::{select, Either};
+use embassy_rp::flash::{Blocking, Flash};
 use embassy_rp::{bind_interrupts, gpio, i2c, peripherals, usb};
-use embassy_time::Timer;
+use embassy_time::{Delay, Duration, Instant, Ticker, Timer};
+use embassy_usb::class::cdc_acm::{CdcAcmClass, State};
+use embassy_usb::driver::EndpointError;
+use embassy_usb::UsbDevice;
+
+/// Events reported by the driver, `Verbose` also logs the PDOs.
+const VERBOSITY: Verbosity = Verbosity::Summary;
 
 bind_interrupts!(struct Irqs {
     USBCTRL_IRQ => usb::InterruptHandler<peripherals::USB>;
     I2C0_IRQ => i2c::InterruptHandler<peripherals::I2C0>;
 });
 
-use rusty_picopd::ap33772::regs::*;
+use rusty_picopd::ap33772::regs::{IrqMask, PDO};
 use rusty_picopd::ap33772::*;
 
 #[embassy_executor::main]
-async fn main(spawner: Spawner) {
+async fn main(_spawner: Spawner) {
     let p = embassy_rp::init(Default::default());
 
-    let mut pwr_en = gpio::Output::new(p.PIN_23, gpio::Level::Low);
+    let pwr_en = gpio::Output::new(p.PIN_23, gpio::Level::Low);
     let pdc_irq = gpio::Input::new(p.PIN_24, gpio::Pull::None);
-    let led = gpio::Output::new(p.PIN_25, gpio::Level::Low);
-    spawner.spawn(blink_led(led)).unwrap();
+    let mut led = gpio::Output::new(p.PIN_25, gpio::Level::Low);
 
     let i2c = i2c::I2c::new_async(p.I2C0, p.PIN_1, p.PIN_0, Irqs, i2c::Config::default());
     // let i2c = i2c::I2c::new_blocking(p.I2C0, p.PIN_1, p.PIN_0, i2c::Config::default());
-    let i2c_ref_cell = RefCell::new(i2c);
 
-    let mut pdc = AP33772::new(bus_i2c::RefCellDevice::new(&i2c_ref_cell));
+    let mut pdc = AP33772::new(i2c)
+        .with_enable_pin(pwr_en)
+        .with_observer(Filtered::new(VERBOSITY, |event: PdEvent| {
+            info!("event: {}", event)
+        }));
+
+    let mut serial = [0; 16];
+    let serial = flash_serial(p.FLASH, &mut serial);
+    let mut usb_bufs = UsbBuffers::new();
+    let (mut usb_dev, mut cdc) = build_cdc(usb::Driver::new(p.USB, Irqs), serial, &mut usb_bufs);
 
     // initialisation
-    Timer::after_millis(10).await;
-    let _ = pdc.read_pdos();
-    let _ = pdc.write_tr([0x10, 0x27, 0x41, 0x10, 0x88, 0x07, 0xce, 0x03]);
-    let _ = pdc.write_irqmask(0xf7);
-    let _ = pdc.write_ocpthr(100);
-    let _ = pdc.write_otpthr(20);
-    let _ = pdc.write_drthr(80);
-
-    // choose profile
-    let v_nom = 4400;
-    let v_min = 3300;
-    let v_max = 5000;
-    let i_nom = 100;
-    let i_min = 100;
-    let mut ipdo_sel: Option<usize> = None;
-    let mut pdo_sel: Option<&PDO> = None;
-    for (i, pdo_opt) in pdc.pdos.iter().enumerate() {
-        if let Some(pdo) = pdo_opt {
-            info!(
-                "pdo[{}]: {} - {} mV, {} mA",
-                i + 1,
-                pdo.vmin(),
-                pdo.vmax(),
-                pdo.imax(),
-            );
-            if pdo.vcomp(v_min, v_max) && pdo.icomp(i_min) {
-                info!("compatible");
-                match (pdo, pdo_sel) {
-                    (_, None) => {
-                        info!("selecting");
-                        pdo_sel = Some(&pdo);
-                        ipdo_sel = Some(i);
-                    }
-                    (PDO::Programmable(_), Some(PDO::Fixed(_))) => {
-                        info!("selecting");
-                        pdo_sel = Some(&pdo);
-                        ipdo_sel = Some(i);
-                    }
-                    (PDO::Fixed(_), Some(PDO::Fixed(pdo_old))) => {
-                        if pdo.imax() > pdo_old.imax() {
-                            info!("selecting");
-                            pdo_sel = Some(&pdo);
-                            ipdo_sel = Some(i);
-                        }
-                    }
-                    (PDO::Programmable(_), Some(PDO::Programmable(pdo_old))) => {
-                        if pdo.imax() > pdo_old.imax() {
-                            info!("selecting");
-                            pdo_sel = Some(&pdo);
-                            ipdo_sel = Some(i);
-                        }
-                    }
-                    _ => {}
-                }
-            }
-        }
+    if !matches!(pdc.wait_ready(&mut Delay, 100), Ok(true)) {
+        warn!("AP33772 not ready");
     }
-
-    // request profile
-    match (pdo_sel, ipdo_sel) {
-        (Some(PDO::Programmable(pdo)), Some(ipdo)) => {
-            let mut ardo = ARDO(0);
-            ardo.pos((ipdo + 1).try_into().unwrap());
-            let v_set = cmp::max(cmp::min(v_nom, v_max), v_min);
-            let i_set = cmp::min(i_nom, pdo.imax() * 50);
-            ardo.volt(v_set / 20);
-            ardo.i(i_set / 50);
-            let _ = pdc.write_rdo(&RDO::ARDO(ardo));
+    let init = pdc.init(&InitConfig {
+        // 10k NTC, B = 3435; an empty table would be rejected by init()
+        tr: ntc_table(10_000, 3435).unwrap_or_default(),
+        config: DeviceConfig {
+            irq_mask: IrqMask::all(),
+            ocp_ma: 100,
+            otp_c: 20,
+            dr_c: 80,
+        },
+    });
+    if let Err(err) = init {
+        match err {
+            Error::UnexpectedDevice => error!("No AP33772 found"),
+            _ => error!("AP33772 initialisation failed"),
         }
-        (Some(PDO::Fixed(pdo)), Some(ipdo)) => {
-            let mut frdo = FixedRDO(0);
-            frdo.pos((ipdo + 1).try_into().unwrap());
-            let i_set = cmp::min(i_nom, pdo.imax() * 10);
-            frdo.i(i_set / 10);
-            frdo.imax(i_set / 10);
-            let _ = pdc.write_rdo(&RDO::FixedRDO(frdo));
+        // keep the output off without the protection thresholds
+        if pdc.allow_output(false).is_err() {
+            warn!("Failed to disable the output");
         }
-        _ => {}
     }
 
-    // enable power if negotiation successful
-    Timer::after_millis(100).await;
+    // choose and request profile
+    if VERBOSITY >= Verbosity::Verbose {
+        for (pos, pdo) in pdc.valid_pdos() {
+            log_pdo(pos, pdo);
+        }
+    }
+    // fall back to plain 5 V if the preferred request is not accepted
+    let reqs = [
+        PowerRequest {
+            v_nom: 4400,
+            v_min: 3300,
+            v_max: 5000,
+            i_nom: 100,
+            i_min: 100,
+        },
+        PowerRequest {
+            v_nom: 5000,
+            v_min: 5000,
+            v_max: 5000,
+            i_nom: 100,
+            i_min: 100,
+        },
+    ];
+    // power is enabled by the driver if negotiation successful
+    let negotiated = pdc.negotiate_fallback(&reqs, SelectionPolicy::default(), &mut Delay, 500);
     let irq_state = pdc_irq.is_high();
-    let _ = pdc.update();
     info!("Status: 0b{:08b} - {}", pdc.status.0, irq_state);
-    if pdc.status.ready() && pdc.status.success() {
-        info!("Enabling output");
-        pwr_en.set_high();
+    match negotiated {
+        Ok((ireq, pos)) => info!("request {} accepted for pdo[{}]", ireq, pos.get()),
+        Err(Error::NoCompatiblePdo) => info!("no compatible pdo"),
+        Err(Error::NegotiationRejected) => warn!("Request rejected by the source"),
+        Err(_) => warn!("Negotiation failed"),
     }
+    if negotiated.is_err() && pdc.ensure_safe_default().is_ok() {
+        info!("Requested 5 V safe default");
+    }
+    if pdc.output_enabled() {
+        info!("Output enabled");
+        if !pdc.verify_output(500).unwrap_or(false) {
+            warn!("VBUS does not match the contract, disabling output");
+            if pdc.allow_output(false).is_err() {
+                warn!("Failed to disable the output");
+            }
+        }
+    }
+    if let Some((volt, curr)) = pdc.active_contract() {
+        info!("Contract: {} mV, {} mA", volt, curr);
+    }
+
+    let pdc = RefCell::new(pdc);
 
     let update_fut = async {
         // pdc_irq.wait_for_high().await;
@@ -136,48 +138,439 @@ async fn main(spawner: Spawner) {
 
     // monitor
     let write_fut = async {
+        let mut state = NegotiationState::default();
         loop {
-            let temp = pdc.read_temp().unwrap();
-            let volt = pdc.read_voltage().unwrap();
-            let curr = pdc.read_current().unwrap();
-            pdc.update().unwrap();
-            let irq_trgd = pdc_irq.is_high();
-
-            info!(
-                "irq: {}, status: b'{:08b}, volt: {} mV, curr: {} mA, temp: {} degC",
-                irq_trgd, pdc.status.0, volt, curr, temp,
-            );
-            if pdc.status.ovp() || pdc.status.ocp() || pdc.status.otp() {
-                info!("Switching off power!");
-                pwr_en.set_low();
-            }
-            if pdc.status.newpdos() {
-                for (i, pdo_opt) in pdc.pdos.iter().enumerate() {
-                    if let Some(pdo) = pdo_opt {
-                        info!(
-                            "pdo[{}]: {} - {} mV, {} mA",
-                            i + 1,
-                            pdo.vmin(),
-                            pdo.vmax(),
-                            pdo.imax(),
-                        );
+            'monitor: {
+                let mut pdc = pdc.borrow_mut();
+                let Ok(t) = pdc.retry(&mut Delay, |pdc| pdc.measure_all()) else {
+                    warn!("AP33772 not responding");
+                    break 'monitor;
+                };
+                let event = PdEvent::from_status(&t.status);
+                let irq_trgd = pdc_irq.is_high();
+
+                info!(
+                    "irq: {}, status: b'{:08b}, volt: {} mV, curr: {} mA, temp: {} degC",
+                    irq_trgd, t.status.0, t.voltage_mv, t.current_ma, t.temp,
+                );
+                if pdc.handle_derating().is_err() {
+                    warn!("Failed to adjust the current for derating");
+                }
+                if let Ok(true) = pdc.tick(Instant::now().as_millis()) {
+                    warn!("No contract, request re-issued");
+                }
+                let prev_state = state;
+                match state.step(t.status) {
+                    s if s == prev_state => {}
+                    NegotiationState::Idle => info!("Source detached"),
+                    NegotiationState::ReadingPdos if VERBOSITY >= Verbosity::Verbose => {
+                        for (pos, pdo) in pdc.valid_pdos() {
+                            log_pdo(pos, pdo);
+                        }
+                    }
+                    NegotiationState::ReadingPdos => {}
+                    s => info!("Negotiation: {}", s),
+                }
+                if prev_state == NegotiationState::Faulted && state != prev_state {
+                    info!("Fault cleared");
+                }
+                match event {
+                    PdEvent::Fault(FaultKind::Derating) => {
+                        info!("Derating, reducing current");
+                    }
+                    PdEvent::Fault(kind) => {
+                        info!("Power switched off on {}!", kind);
                     }
+                    _ => {}
                 }
             }
             Timer::after_secs(5).await;
         }
     };
 
-    join::join(write_fut, update_fut).await;
+    // indicate the link state on the LED
+    let blink_fut = async {
+        loop {
+            let state = pdc.borrow().link_state();
+            let (on_ms, off_ms) = blink_pattern(state);
+            if on_ms > 0 {
+                led.set_high();
+                Timer::after_millis(on_ms).await;
+            }
+            if off_ms > 0 {
+                led.set_low();
+                Timer::after_millis(off_ms).await;
+            }
+        }
+    };
+
+    // command interpreter and telemetry on the serial console
+    let cdc_fut = async {
+        loop {
+            cdc.wait_connection().await;
+            info!("CDC connected");
+            let _ = serve_cdc(&mut cdc, &pdc).await;
+            info!("CDC disconnected");
+        }
+    };
+
+    join::join5(usb_dev.run(), cdc_fut, write_fut, update_fut, blink_fut).await;
+}
+
+/// Size of the flash chip, see `memory.x`.
+const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+/// Format the unique ID of the flash chip into `buf` as hex digits, to give
+/// every board its own USB serial number.
+fn flash_serial(flash: peripherals::FLASH, buf: &mut [u8; 16]) -> &str {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+
+    let mut uid = [0; 8];
+    let mut flash = Flash::<_, Blocking, FLASH_SIZE>::new_blocking(flash);
+    if flash.blocking_unique_id(&mut uid).is_err() {
+        warn!("Failed to read the flash unique ID");
+    }
+    for (digits, b) in buf.chunks_mut(2).zip(uid) {
+        digits[0] = HEX[(b >> 4) as usize];
+        digits[1] = HEX[(b & 0xf) as usize];
+    }
+    core::str::from_utf8(buf).unwrap_or_default()
+}
+
+type UsbDriver<'d> = usb::Driver<'d, peripherals::USB>;
+
+/// Descriptor and control buffers of the USB device, see `build_cdc()`.
+struct UsbBuffers<'d> {
+    device_descriptor: [u8; 256],
+    config_descriptor: [u8; 256],
+    bos_descriptor: [u8; 256],
+    control: [u8; 64],
+    cdc_state: State<'d>,
 }
 
-#[embassy_executor::task]
-async fn blink_led(mut led: gpio::Output<'static, impl gpio::Pin + 'static>) {
+impl UsbBuffers<'_> {
+    fn new() -> Self {
+        Self {
+            device_descriptor: [0; 256],
+            config_descriptor: [0; 256],
+            bos_descriptor: [0; 256],
+            control: [0; 64],
+            cdc_state: State::new(),
+        }
+    }
+}
+
+/// Build the USB device with a single CDC ACM class for the serial console,
+/// reporting `serial` as its serial number.
+fn build_cdc<'d>(
+    driver: UsbDriver<'d>,
+    serial: &'d str,
+    bufs: &'d mut UsbBuffers<'d>,
+) -> (UsbDevice<'d, UsbDriver<'d>>, CdcAcmClass<'d, UsbDriver<'d>>) {
+    let mut usb_cfg = embassy_usb::Config::new(0x1556, 0xcafe);
+    usb_cfg.manufacturer = Some("qgp");
+    usb_cfg.product = Some("rusty-picopd");
+    usb_cfg.serial_number = Some(serial);
+    usb_cfg.max_power = 100;
+    usb_cfg.max_packet_size_0 = 64;
+    // required for windows compatibility
+    usb_cfg.device_class = 0xef;
+    usb_cfg.device_sub_class = 0x02;
+    usb_cfg.device_protocol = 0x01;
+    usb_cfg.composite_with_iads = true;
+
+    let mut usb_builder = embassy_usb::Builder::new(
+        driver,
+        usb_cfg,
+        &mut bufs.device_descriptor,
+        &mut bufs.config_descriptor,
+        &mut bufs.bos_descriptor,
+        &mut [],
+        &mut bufs.control,
+    );
+    let cdc = CdcAcmClass::new(&mut usb_builder, &mut bufs.cdc_state, 64);
+    (usb_builder.build(), cdc)
+}
+
+fn log_pdo(pos: usize, pdo: &PDO) {
+    info!(
+        "pdo[{}]: {} - {} mV, {} mA, {} mW",
+        pos,
+        pdo.vmin(),
+        pdo.vmax(),
+        pdo.imax(),
+        pdo.max_power_mw(),
+    );
+    match pdo {
+        PDO::Fixed(fixed) => info!(
+            "peak current: {}, dual-role power: {}, dual-role data: {}, usb comms: {}, unconstrained: {}",
+            fixed.peak_current(),
+            fixed.dual_role_power(),
+            fixed.dual_role_data(),
+            fixed.usb_comms_capable(),
+            fixed.unconstrained_power(),
+        ),
+        PDO::Programmable(apdo) => {
+            info!("power limited: {}", apdo.pps_power_limited());
+            if apdo.reserved() != 0 {
+                warn!("reserved bits set: {:#010x}", apdo.reserved());
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+enum Command {
+    Status,
+    Pdos { json: bool },
+    Set { voltage_mv: u16, current_ma: u16 },
+    Enable,
+    Disable,
+    Telemetry { period_ms: u32, binary: bool },
+    Regs,
+}
+
+/// Parse a single line like `set 9000 1500` into a command. `telemetry
+/// <ms> bin` switches the telemetry to the frames of `Telemetry::to_frame()`.
+fn parse_command(line: &[u8]) -> Option<Command> {
+    let line = core::str::from_utf8(line).ok()?;
+    let mut args = line.split_ascii_whitespace();
+    let cmd = match args.next()? {
+        "status" => Command::Status,
+        "pdos" => match args.next() {
+            None => Command::Pdos { json: false },
+            Some("json") => Command::Pdos { json: true },
+            Some(_) => return None,
+        },
+        "set" => Command::Set {
+            voltage_mv: args.next()?.parse().ok()?,
+            current_ma: args.next()?.parse().ok()?,
+        },
+        "enable" => Command::Enable,
+        "disable" => Command::Disable,
+        "telemetry" => Command::Telemetry {
+            period_ms: args.next()?.parse().ok()?,
+            binary: match args.next() {
+                None => false,
+                Some("bin") => true,
+                Some(_) => return None,
+            },
+        },
+        "regs" => Command::Regs,
+        _ => return None,
+    };
+    args.next().is_none().then_some(cmd)
+}
+
+fn run_command<I2C: I2c, EN: OutputPin, OBS: PdObserver>(
+    pdc: &mut AP33772<I2C, EN, 7, OBS>,
+    cmd: Command,
+    out: &mut impl Write,
+) -> fmt::Result {
+    match cmd {
+        Command::Status => {
+            // the temperature stays empty without an NTC table
+            let temp = pdc.read_temp().ok();
+            let volt = pdc.read_voltage().map_err(|_| fmt::Error)?;
+            let curr = pdc.read_current().map_err(|_| fmt::Error)?;
+            pdc.update().map_err(|_| fmt::Error)?;
+            core::write!(
+                out,
+                "status: 0x{:02x}, volt: {} mV, curr: {} mA, temp: ",
+                pdc.status.0,
+                volt,
+                curr,
+            )?;
+            if let Some(temp) = temp {
+                core::write!(out, "{} degC", temp)?;
+            }
+            core::write!(out, "\r\n")
+        }
+        Command::Pdos { json } => {
+            for (pos, pdo) in pdc.valid_pdos() {
+                if json {
+                    pdo.write_record(out, pos)?;
+                    out.write_str("\r\n")?;
+                } else {
+                    core::write!(out, "pdo[{}]: {}\r\n", pos, pdo)?;
+                }
+            }
+            Ok(())
+        }
+        Command::Set {
+            voltage_mv,
+            current_ma,
+        } => match pdc.request_voltage(voltage_mv, current_ma) {
+            Ok(pos) => core::write!(out, "requested pdo[{}]\r\n", pos.get()),
+            Err(_) => core::write!(out, "no compatible pdo\r\n"),
+        },
+        Command::Enable | Command::Disable => {
+            let enabled = pdc
+                .allow_output(cmd == Command::Enable)
+                .map_err(|_| fmt::Error)?;
+            core::write!(out, "output: {}\r\n", if enabled { "on" } else { "off" })
+        }
+        Command::Regs => {
+            let dump = pdc.dump_registers().map_err(|_| fmt::Error)?;
+            core::write!(out, "{:02x?}\r\n", dump)
+        }
+        // handled by the caller
+        Command::Telemetry { .. } => Ok(()),
+    }
+}
+
+/// Header of the telemetry lines written to the serial console.
+const TELEMETRY_HEADER: &str = "volt_mv,curr_ma,temp,npdos,status_hex\r\n";
+
+/// Default period of the telemetry lines, 0 disables them.
+const TELEMETRY_PERIOD_MS: u32 = 1000;
+
+/// Write one CSV telemetry line, see `TELEMETRY_HEADER`.
+fn write_telemetry<I2C: I2c, EN: OutputPin, OBS: PdObserver>(
+    pdc: &mut AP33772<I2C, EN, 7, OBS>,
+    out: &mut impl Write,
+) -> fmt::Result {
+    let npdos = pdc.read_npdos().map_err(|_| fmt::Error)?;
+    let t = pdc.measure_all().map_err(|_| fmt::Error)?;
+
+    // the temperature column stays empty without an NTC table
+    core::write!(out, "{},{},", t.voltage_mv, t.current_ma)?;
+    if let Some(temp) = t.temp {
+        core::write!(out, "{}", temp)?;
+    }
+    core::write!(out, ",{},{:02x}\r\n", npdos, t.status.0)
+}
+
+async fn write_text<'d>(
+    cdc: &mut CdcAcmClass<'d, UsbDriver<'d>>,
+    text: &[u8],
+) -> Result<(), EndpointError> {
+    for chunk in text.chunks(cdc.max_packet_size().into()) {
+        cdc.write_packet(chunk).await?;
+    }
+    Ok(())
+}
+
+async fn serve_cdc<'d, I2C: I2c, EN: OutputPin, OBS: PdObserver>(
+    cdc: &mut CdcAcmClass<'d, UsbDriver<'d>>,
+    pdc: &RefCell<AP33772<I2C, EN, 7, OBS>>,
+) -> Result<(), EndpointError> {
+    let mut period_ms = TELEMETRY_PERIOD_MS;
+    let mut binary = false;
+    let mut seq = 0u8;
+    let mut ticker = Ticker::every(Duration::from_millis(period_ms.max(1).into()));
+    let mut pkt = [0; 64];
+    let mut line = [0; 64];
+    let mut len = 0;
+
+    write_text(cdc, TELEMETRY_HEADER.as_bytes()).await?;
     loop {
-        led.set_high();
-        Timer::after_secs(1).await;
+        let tick = async {
+            match period_ms {
+                0 => future::pending().await,
+                _ => ticker.next().await,
+            }
+        };
+        let ev = select(cdc.read_packet(&mut pkt), tick).await;
+        let n = match ev {
+            Either::First(n) => n?,
+            Either::Second(()) if binary => {
+                let frame = pdc.borrow_mut().measure_all().map(|t| t.to_frame(seq));
+                if let Ok(frame) = frame {
+                    cdc.write_packet(&frame).await?;
+                    seq = seq.wrapping_add(1);
+                }
+                continue;
+            }
+            Either::Second(()) => {
+                let mut reply = TextBuf::<64>::new();
+                if write_telemetry(&mut pdc.borrow_mut(), &mut reply).is_ok() {
+                    write_text(cdc, reply.as_bytes()).await?;
+                }
+                continue;
+            }
+        };
 
-        led.set_low();
-        Timer::after_secs(1).await;
+        for &c in &pkt[..n] {
+            if c != b'\r' && c != b'\n' {
+                if len < line.len() {
+                    line[len] = c;
+                }
+                len += 1;
+                continue;
+            }
+            if len == 0 {
+                continue;
+            }
+
+            let mut reply = TextBuf::<512>::new();
+            let res = match parse_command(&line[..len.min(line.len())]) {
+                Some(Command::Telemetry {
+                    period_ms: p,
+                    binary: b,
+                }) if len <= line.len() => {
+                    period_ms = p;
+                    binary = b;
+                    ticker = Ticker::every(Duration::from_millis(period_ms.max(1).into()));
+                    core::write!(reply, "telemetry: {} ms\r\n", period_ms)
+                }
+                Some(cmd) if len <= line.len() => {
+                    info!("command: {}", cmd);
+                    run_command(&mut pdc.borrow_mut(), cmd, &mut reply)
+                }
+                _ => core::write!(reply, "unknown command\r\n"),
+            };
+            if res.is_err() {
+                reply.clear();
+                let _ = core::write!(reply, "error\r\n");
+            }
+            write_text(cdc, reply.as_bytes()).await?;
+            len = 0;
+        }
+    }
+}
+
+/// Fixed size text buffer to format replies into.
+struct TextBuf<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> TextBuf<N> {
+    fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl<const N: usize> Write for TextBuf<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > N {
+            return Err(fmt::Error);
+        }
+        self.buf[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// LED on and off times in ms for each link state.
+fn blink_pattern(state: LinkState) -> (u64, u64) {
+    match state {
+        LinkState::Disconnected => (50, 1950),
+        LinkState::Negotiating => (250, 250),
+        LinkState::Active => (1000, 0),
+        LinkState::Fault => (100, 100),
     }
 }
@@ -0,0 +1,1286 @@
+use embedded_hal_mock::eh1::delay::NoopDelay;
+use embedded_hal_mock::eh1::i2c::{Mock, Transaction};
+
+use rusty_picopd::ap33772::regs::*;
+use rusty_picopd::ap33772::*;
+
+const ADDR: u8 = 0x51;
+
+fn status(status: u8) -> Transaction {
+    Transaction::write_read(ADDR, vec![0x1d], vec![status])
+}
+
+fn tr_writes(tr: [u8; 8]) -> Vec<Transaction> {
+    (0x28..)
+        .step_by(2)
+        .zip(tr.chunks(2))
+        .flat_map(|(reg, val)| {
+            [
+                Transaction::transaction_start(ADDR),
+                Transaction::write(ADDR, vec![reg]),
+                Transaction::write(ADDR, val.to_vec()),
+                Transaction::transaction_end(ADDR),
+            ]
+        })
+        .collect()
+}
+
+fn rdo(rdo: u32) -> Transaction {
+    Transaction::write(ADDR, [&[0x30], &rdo.to_le_bytes()[..]].concat())
+}
+
+fn pdos(pdos: &[u32]) -> [Transaction; 2] {
+    let buf = pdos.iter().flat_map(|pdo| pdo.to_le_bytes()).collect();
+    [
+        Transaction::write_read(ADDR, vec![0x1c], vec![pdos.len() as u8]),
+        Transaction::write_read(ADDR, vec![0x00], buf),
+    ]
+}
+
+#[test]
+fn not_ready() {
+    let mut i2c = Mock::new(&[status(0x00)]);
+    let mut pdc = AP33772::new(i2c.clone());
+    assert_eq!(pdc.update().unwrap(), PdEvent::Nothing);
+    i2c.done();
+}
+
+#[test]
+fn newpdos_reads_pdos() {
+    // 5 V / 3 A fixed, 3.3 - 11 V / 3 A programmable
+    let [npdos, pdos] = pdos(&[0x0001_912c, 0xc0dc_213c]);
+    let mut i2c = Mock::new(&[status(0x05), npdos, pdos]);
+    let mut pdc = AP33772::new(i2c.clone());
+    assert_eq!(pdc.update().unwrap(), PdEvent::NewPdos);
+    assert_eq!(pdc.pdo_count(), 2);
+    assert!(matches!(pdc.pdos[0], Some(PDO::Fixed(_))));
+    assert!(matches!(pdc.pdos[1], Some(PDO::Programmable(_))));
+    assert!(pdc.pdos[2].is_none());
+    i2c.done();
+}
+
+#[test]
+fn newpdos_ignored_if_not_ready() {
+    let mut i2c = Mock::new(&[status(0x04)]);
+    let mut pdc = AP33772::new(i2c.clone());
+    assert_eq!(pdc.update().unwrap(), PdEvent::Nothing);
+    assert_eq!(pdc.pdo_count(), 0);
+    i2c.done();
+}
+
+#[test]
+fn negotiated() {
+    let mut i2c = Mock::new(&[status(0x01), status(0x03)]);
+    let mut pdc = AP33772::new(i2c.clone());
+    assert_eq!(pdc.update().unwrap(), PdEvent::Ready);
+    assert_eq!(pdc.update().unwrap(), PdEvent::Negotiated);
+    i2c.done();
+}
+
+#[test]
+fn faults() {
+    let mut i2c = Mock::new(&[
+        status(0x13),
+        status(0x23),
+        status(0x43),
+        status(0x83),
+        status(0x73),
+    ]);
+    let mut pdc = AP33772::new(i2c.clone());
+    assert_eq!(pdc.update().unwrap(), PdEvent::Fault(FaultKind::Ovp));
+    assert_eq!(pdc.update().unwrap(), PdEvent::Fault(FaultKind::Ocp));
+    assert_eq!(pdc.update().unwrap(), PdEvent::Fault(FaultKind::Otp));
+    assert_eq!(pdc.update().unwrap(), PdEvent::Fault(FaultKind::Derating));
+    // OVP takes precedence
+    assert_eq!(pdc.update().unwrap(), PdEvent::Fault(FaultKind::Ovp));
+    i2c.done();
+}
+
+#[test]
+fn status_changed() {
+    let mut i2c = Mock::new(&[status(0x03), status(0x03), status(0x23)]);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.update().unwrap();
+    assert!(pdc.status_changed());
+    pdc.update().unwrap();
+    assert!(!pdc.status_changed());
+    pdc.update().unwrap();
+    assert!(pdc.status_changed());
+    i2c.done();
+}
+
+#[test]
+fn fewer_pdos_clear_stale() {
+    let [npdos_2, pdos_2] = pdos(&[0x0001_912c, 0xc0dc_213c]);
+    let [npdos_1, pdos_1] = pdos(&[0x0001_912c]);
+    let mut i2c = Mock::new(&[status(0x05), npdos_2, pdos_2, status(0x05), npdos_1, pdos_1]);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.update().unwrap();
+    assert_eq!(pdc.pdo_count(), 2);
+    pdc.update().unwrap();
+    assert_eq!(pdc.pdo_count(), 1);
+    assert!(pdc.pdos[1].is_none());
+    i2c.done();
+}
+
+#[test]
+fn link_state() {
+    let mut i2c = Mock::new(&[status(0x00), status(0x01), status(0x03), status(0x23)]);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.update().unwrap();
+    assert_eq!(pdc.link_state(), LinkState::Disconnected);
+    pdc.update().unwrap();
+    assert_eq!(pdc.link_state(), LinkState::Negotiating);
+    pdc.update().unwrap();
+    assert_eq!(pdc.link_state(), LinkState::Active);
+    pdc.update().unwrap();
+    assert_eq!(pdc.link_state(), LinkState::Fault);
+    i2c.done();
+}
+
+#[test]
+fn detach_clears_pdos() {
+    let [npdos, pdos] = pdos(&[0x0001_912c]);
+    let mut i2c = Mock::new(&[status(0x05), npdos, pdos, status(0x00)]);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.update().unwrap();
+    assert!(pdc.is_attached());
+    assert_eq!(pdc.pdo_count(), 1);
+    pdc.update().unwrap();
+    assert!(!pdc.is_attached());
+    assert_eq!(pdc.pdo_count(), 0);
+    i2c.done();
+}
+
+#[test]
+fn measure_all() {
+    let tr = ntc_table(10_000, 3435).unwrap();
+    let measure = [
+        Transaction::write_read(ADDR, vec![0x20], vec![62]),
+        Transaction::write_read(ADDR, vec![0x21], vec![125]),
+        Transaction::write_read(ADDR, vec![0x22], vec![25]),
+        status(0x03),
+    ];
+    let mut i2c = Mock::new(&[&measure[..], &tr_writes(tr), &measure].concat());
+    let mut pdc = AP33772::new(i2c.clone());
+    let t = pdc.measure_all().unwrap();
+    assert_eq!((t.voltage_mv, t.current_ma, t.temp), (4960, 3000, None));
+    assert_eq!(t.status, Status(0x03));
+    pdc.write_tr(tr).unwrap();
+    assert_eq!(pdc.measure_all().unwrap().temp, Some(25));
+    i2c.done();
+}
+
+#[test]
+fn read_thr_per_register() {
+    let mut i2c = Mock::new(&[
+        Transaction::write_read(ADDR, vec![0x23], vec![2]),
+        Transaction::write_read(ADDR, vec![0x24], vec![20]),
+        Transaction::write_read(ADDR, vec![0x25], vec![80]),
+    ]);
+    let mut pdc = AP33772::new(i2c.clone());
+    assert_eq!(pdc.read_thr().unwrap(), [2, 20, 80]);
+    i2c.done();
+}
+
+#[test]
+fn register_access_at_address() {
+    const ADDR2: u8 = 0x52;
+    let mut i2c = Mock::new(&[
+        Transaction::write_read(ADDR2, vec![0x30], vec![0x2c, 0xb1, 0x04, 0x10]),
+        // register address and data in one transaction, without a stop
+        Transaction::transaction_start(ADDR2),
+        Transaction::write(ADDR2, vec![0x28]),
+        Transaction::write(ADDR2, vec![0x10, 0x27]),
+        Transaction::transaction_end(ADDR2),
+        Transaction::write_read(ADDR2, vec![0x20], vec![62]),
+        Transaction::write_read(ADDR2, vec![0x21], vec![125]),
+    ]);
+    let mut pdc = AP33772::new_with_address(i2c.clone(), ADDR2);
+    let mut buf = [0; 4];
+    pdc.read_register(0x30, &mut buf).unwrap();
+    assert_eq!(u32::from_le_bytes(buf), 0x1004_b12c);
+    pdc.write_register(0x28, &10_000u16.to_le_bytes()).unwrap();
+    assert_eq!(pdc.read_voltage().unwrap(), 4960);
+    assert_eq!(pdc.read_current().unwrap(), 3000);
+    i2c.done();
+}
+
+#[test]
+fn request_max_power() {
+    // 5 V / 3 A fixed, 3.3 - 11 V / 3 A programmable
+    let [npdos, pdos] = pdos(&[0x0001_912c, 0xc0dc_213c]);
+    let mut i2c = Mock::new(&[status(0x05), npdos, pdos, rdo(0x2004_4c3c)]);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.update().unwrap();
+    let pos = pdc.request_max_power().unwrap();
+    assert_eq!(pos, PdoPosition::new(2));
+    i2c.done();
+}
+
+#[test]
+fn read_current_avg() {
+    let mut i2c = Mock::new(&[
+        Transaction::write_read(ADDR, vec![0x21], vec![100]),
+        Transaction::write_read(ADDR, vec![0x21], vec![104]),
+        Transaction::write_read(ADDR, vec![0x21], vec![101]),
+        Transaction::write_read(ADDR, vec![0x21], vec![103]),
+    ]);
+    let mut pdc = AP33772::new(i2c.clone());
+    assert_eq!(pdc.read_current_avg(4).unwrap(), 102 * 24);
+    i2c.done();
+}
+
+#[test]
+fn release() {
+    let i2c = Mock::new(&[status(0x03)]);
+    let mut pdc = AP33772::new(i2c);
+    pdc.update().unwrap();
+    pdc.release().done();
+}
+
+#[test]
+fn fewer_slots_than_pdos() {
+    let [_, pdos] = pdos(&[0x0001_912c, 0xc0dc_213c]);
+    let mut i2c = Mock::new(&[
+        status(0x05),
+        Transaction::write_read(ADDR, vec![0x1c], vec![3]),
+        pdos,
+    ]);
+    let mut pdc = AP33772::<_, _, 2>::new_with_slots(i2c.clone(), ADDR);
+    pdc.update().unwrap();
+    assert_eq!(pdc.pdo_count(), 2);
+    i2c.done();
+}
+
+#[test]
+fn negotiation_rejected() {
+    let mut i2c = Mock::new(&[status(0x01), status(0x01), status(0x01)]);
+    let mut pdc = AP33772::new(i2c.clone());
+    let res = pdc.await_negotiation(&mut NoopDelay, 20);
+    assert!(matches!(res, Err(Error::NegotiationRejected)));
+    i2c.done();
+}
+
+#[test]
+fn negotiation_timeout_not_ready() {
+    let mut i2c = Mock::new(&[status(0x00), status(0x00)]);
+    let mut pdc = AP33772::new(i2c.clone());
+    assert!(!pdc.await_negotiation(&mut NoopDelay, 10).unwrap());
+    i2c.done();
+}
+
+#[test]
+fn negotiate_fallback() {
+    // 5 V / 3 A fixed only, 9 V cannot be satisfied
+    let [npdos, pdos] = pdos(&[0x0001_912c]);
+    let mut i2c = Mock::new(&[status(0x05), npdos, pdos, rdo(0x1000_280a), status(0x03)]);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.update().unwrap();
+    let req = |v| PowerRequest {
+        v_nom: v,
+        v_min: v,
+        v_max: v,
+        i_nom: 100,
+        i_min: 100,
+    };
+    let res = pdc.negotiate_fallback(
+        &[req(9000), req(5000)],
+        SelectionPolicy::default(),
+        &mut NoopDelay,
+        100,
+    );
+    assert_eq!(res.unwrap(), (1, PdoPosition::new(1).unwrap()));
+    i2c.done();
+}
+
+#[test]
+fn fault_clears() {
+    let mut i2c = Mock::new(&[status(0x23), status(0x23), status(0x03)]);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.update().unwrap();
+    assert!(pdc.status.fault());
+    pdc.update().unwrap();
+    assert_eq!(pdc.link_state(), LinkState::Fault);
+    pdc.update().unwrap();
+    assert!(!pdc.status.fault());
+    assert_eq!(pdc.link_state(), LinkState::Active);
+    i2c.done();
+}
+
+#[test]
+fn derating_info() {
+    let thr = |temp| {
+        [
+            Transaction::write_read(ADDR, vec![0x22], vec![temp]),
+            Transaction::write_read(ADDR, vec![0x25], vec![80]),
+        ]
+    };
+    let tr = ntc_table(10_000, 3435).unwrap();
+    let mut i2c = Mock::new(
+        &[
+            tr_writes(tr),
+            thr(60).into(),
+            thr(75).into(),
+            thr(80).into(),
+        ]
+        .concat(),
+    );
+    let mut pdc = AP33772::new(i2c.clone());
+    assert!(matches!(pdc.derating_info(), Err(Error::NtcNotConfigured)));
+    pdc.write_tr(tr).unwrap();
+    assert_eq!(pdc.derating_info().unwrap(), None);
+    assert_eq!(
+        pdc.derating_info().unwrap(),
+        Some(DeratingLevel::Approaching)
+    );
+    assert_eq!(pdc.derating_info().unwrap(), Some(DeratingLevel::Active));
+    i2c.done();
+}
+
+#[test]
+fn read_active_contract() {
+    // 5 V / 3 A fixed
+    let [npdos, pdos] = pdos(&[0x0001_912c]);
+    let mut i2c = Mock::new(&[
+        status(0x05),
+        npdos,
+        pdos,
+        rdo(0x1004_b12c),
+        status(0x03),
+        status(0x00),
+    ]);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.update().unwrap();
+    pdc.request_fixed(0, 3000).unwrap();
+    // the cached status predates the request
+    assert_eq!(pdc.active_contract(), None);
+    assert_eq!(pdc.read_active_contract().unwrap(), Some((5000, 3000)));
+    assert_eq!(pdc.read_active_contract().unwrap(), None);
+    i2c.done();
+}
+
+#[test]
+fn handle_derating_fixed() {
+    // 5 V / 3 A fixed
+    let [npdos, pdos] = pdos(&[0x0001_912c]);
+    let mut i2c = Mock::new(&[
+        status(0x05),
+        npdos,
+        pdos,
+        rdo(0x1004_b12c),
+        status(0x83),
+        // 70 % of the operating and the maximum current
+        rdo(0x1003_48d2),
+        status(0x03),
+        rdo(0x1004_b12c),
+    ]);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.update().unwrap();
+    pdc.request_fixed(0, 3000).unwrap();
+    pdc.update().unwrap();
+    assert!(pdc.handle_derating().unwrap());
+    assert!(!pdc.handle_derating().unwrap());
+    assert_eq!(pdc.active_contract(), Some((5000, 2100)));
+    pdc.update().unwrap();
+    assert!(pdc.handle_derating().unwrap());
+    assert_eq!(pdc.active_contract(), Some((5000, 3000)));
+    i2c.done();
+}
+
+#[test]
+fn handle_derating_pps() {
+    // 5 V / 3 A fixed, 3.3 - 11 V / 3 A programmable
+    let [npdos, pdos] = pdos(&[0x0001_912c, 0xc0dc_213c]);
+    let mut i2c = Mock::new(&[
+        status(0x05),
+        npdos,
+        pdos,
+        rdo(0x2003_8428),
+        status(0x83),
+        rdo(0x2003_841c),
+        status(0x03),
+        rdo(0x2003_8428),
+    ]);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.update().unwrap();
+    pdc.request_pps(1, 9000, 2000).unwrap();
+    pdc.update().unwrap();
+    assert!(pdc.handle_derating().unwrap());
+    assert_eq!(pdc.active_contract(), Some((9000, 1400)));
+    pdc.update().unwrap();
+    assert!(pdc.handle_derating().unwrap());
+    assert_eq!(pdc.active_contract(), Some((9000, 2000)));
+    i2c.done();
+}
+
+#[test]
+fn temp_margin() {
+    let thr = |temp| {
+        [
+            Transaction::write_read(ADDR, vec![0x22], vec![temp]),
+            Transaction::write_read(ADDR, vec![0x24], vec![100]),
+        ]
+    };
+    let tr = ntc_table(10_000, 3435).unwrap();
+    let mut i2c = Mock::new(&[tr_writes(tr), thr(60).into(), thr(105).into()].concat());
+    let mut pdc = AP33772::new(i2c.clone());
+    assert!(matches!(pdc.temp_margin_c(), Err(Error::NtcNotConfigured)));
+    assert!(matches!(pdc.read_temp(), Err(Error::NtcNotConfigured)));
+    pdc.write_tr(tr).unwrap();
+    assert_eq!(pdc.temp_margin_c().unwrap(), 40);
+    assert_eq!(pdc.temp_margin_c().unwrap(), -5);
+    i2c.done();
+}
+
+#[test]
+fn request_voltage() {
+    // 5 V / 3 A fixed, 3.3 - 11 V / 3 A programmable
+    let [npdos, pdos] = pdos(&[0x0001_912c, 0xc0dc_213c]);
+    let mut i2c = Mock::new(&[
+        status(0x05),
+        npdos,
+        pdos,
+        rdo(0x2003_8428),
+        rdo(0x1004_b12c),
+    ]);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.update().unwrap();
+    assert_eq!(pdc.request_voltage(9000, 2000).unwrap().get(), 2);
+    assert_eq!(pdc.request_voltage(5000, 3000).unwrap().get(), 1);
+    assert!(matches!(
+        pdc.request_voltage(15000, 1000),
+        Err(Error::NoCompatiblePdo)
+    ));
+    i2c.done();
+}
+
+#[test]
+fn request_exact_voltage() {
+    // 5 V / 3 A fixed, 3.3 - 11 V / 3 A programmable
+    let [npdos, pdos] = pdos(&[0x0001_912c, 0xc0dc_213c]);
+    let mut i2c = Mock::new(&[
+        status(0x05),
+        npdos,
+        pdos,
+        rdo(0x1004_b12c),
+        rdo(0x2003_843c),
+    ]);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.update().unwrap();
+    assert_eq!(pdc.request_exact_voltage(5000).unwrap().get(), 1);
+    assert_eq!(pdc.request_exact_voltage(9000).unwrap().get(), 2);
+    // not representable in 20 mV steps, out of range
+    for v in [9010, 20000] {
+        assert!(matches!(
+            pdc.request_exact_voltage(v),
+            Err(Error::NoCompatiblePdo)
+        ));
+    }
+    i2c.done();
+}
+
+#[test]
+fn read_full_scale() {
+    let mut i2c = Mock::new(&[
+        Transaction::write_read(ADDR, vec![0x20], vec![0xff]),
+        Transaction::write_read(ADDR, vec![0x21], vec![0xff]),
+    ]);
+    let mut pdc = AP33772::new(i2c.clone());
+    assert_eq!(pdc.read_voltage().unwrap(), 20400);
+    assert_eq!(pdc.read_current().unwrap(), 6120);
+    i2c.done();
+}
+
+#[test]
+fn observer() {
+    let mut i2c = Mock::new(&[status(0x01), status(0x01), status(0x03)]);
+    let mut events = Vec::new();
+    let mut pdc = AP33772::new(i2c.clone()).with_observer(|ev| events.push(ev));
+    pdc.update().unwrap();
+    pdc.update().unwrap();
+    pdc.update().unwrap();
+    drop(pdc);
+    assert_eq!(events, [PdEvent::Ready, PdEvent::Negotiated]);
+    i2c.done();
+}
+
+#[test]
+fn observer_verbosity() {
+    let mut i2c = Mock::new(&[status(0x01), status(0x03), status(0x01), status(0x03)]);
+    let mut events = Vec::new();
+    let observer = Filtered::new(Verbosity::Summary, |ev| events.push(ev));
+    let mut pdc = AP33772::new(i2c.clone()).with_observer(observer);
+    pdc.update().unwrap();
+    pdc.update().unwrap();
+    drop(pdc);
+    assert_eq!(events, [PdEvent::Negotiated]);
+
+    let mut events = Vec::new();
+    let observer = Filtered::new(Verbosity::Quiet, |ev| events.push(ev));
+    let mut pdc = AP33772::new(i2c.clone()).with_observer(observer);
+    pdc.update().unwrap();
+    pdc.update().unwrap();
+    drop(pdc);
+    assert!(events.is_empty());
+    i2c.done();
+}
+
+#[test]
+fn request_pps_regulated() {
+    // 5 V / 3 A fixed, 3.3 - 11 V / 3 A programmable
+    let [npdos, pdos] = pdos(&[0x0001_912c, 0xc0dc_213c]);
+    let volt = |raw: u8| Transaction::write_read(ADDR, vec![0x20], vec![raw]);
+    let mut i2c = Mock::new(&[
+        status(0x05),
+        npdos,
+        pdos,
+        // 9 V sags to 8.72 V, 9.28 V gives 9.04 V
+        rdo(0x2003_843c),
+        volt(109),
+        rdo(0x2003_a03c),
+        volt(113),
+        // a 2 V sag is only compensated up to the target + 500 mV
+        rdo(0x2003_e83c),
+        volt(100),
+        rdo(0x2004_1a3c),
+        volt(110),
+    ]);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.update().unwrap();
+    pdc.request_pps_regulated(1, 9000, &mut NoopDelay).unwrap();
+    pdc.request_pps_regulated(1, 10000, &mut NoopDelay).unwrap();
+    assert!(matches!(
+        pdc.request_pps_regulated(0, 9000, &mut NoopDelay),
+        Err(Error::InvalidPdoIndex)
+    ));
+    i2c.done();
+}
+
+#[test]
+fn ramp_pps() {
+    // 5 V / 3 A fixed, 3.3 - 11 V / 3 A programmable
+    let [npdos, pdos] = pdos(&[0x0001_912c, 0xc0dc_213c]);
+    let mut i2c = Mock::new(&[
+        status(0x05),
+        npdos,
+        pdos,
+        rdo(0x2003_8428),
+        rdo(0x2003_b628),
+        rdo(0x2003_e828),
+    ]);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.update().unwrap();
+    pdc.request_pps(1, 9000, 2000).unwrap();
+    pdc.ramp_pps(1, 10000, 500, &mut NoopDelay, 10).unwrap();
+    i2c.done();
+}
+
+#[test]
+fn pps_clamp() {
+    // 5 V / 3 A fixed, 3.3 - 11 V / 3 A programmable
+    let [npdos, pdos] = pdos(&[0x0001_912c, 0xc0dc_213c]);
+    let mut i2c = Mock::new(&[
+        status(0x05),
+        npdos,
+        pdos,
+        rdo(0x2001_4a28),
+        rdo(0x2004_4c28),
+    ]);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.update().unwrap();
+    // clamped to 3300 mV and 11000 mV
+    pdc.request_pps(1, 2000, 2000).unwrap();
+    pdc.request_pps(1, 12000, 2000).unwrap();
+    i2c.done();
+}
+
+#[test]
+fn pdo_count_mismatch() {
+    // 5 V / 3 A fixed, corrupted second PDO
+    let [npdos, pdos] = pdos(&[0x0001_912c, 0xffff_ffff]);
+    let mut i2c = Mock::new(&[npdos, pdos]);
+    let mut pdc = AP33772::new(i2c.clone());
+    assert!(matches!(pdc.read_pdos(), Err(Error::PdoCountMismatch)));
+    assert_eq!(pdc.pdo_count(), 0);
+    i2c.done();
+}
+
+#[test]
+fn can_supply() {
+    // 5 V / 3 A fixed, 3.3 - 11 V / 3 A programmable
+    let [npdos, pdos] = pdos(&[0x0001_912c, 0xc0dc_213c]);
+    let mut i2c = Mock::new(&[status(0x05), npdos, pdos]);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.update().unwrap();
+    assert_eq!(pdc.can_supply(9000, 2000), Some(1));
+    assert_eq!(pdc.can_supply(5000, 3000), Some(1));
+    assert_eq!(pdc.can_supply(20000, 2000), None);
+    assert_eq!(pdc.can_supply(5000, 3500), None);
+    i2c.done();
+}
+
+#[test]
+fn poll() {
+    let mut i2c = Mock::new(&[status(0x01), status(0x03)]);
+    let mut pdc = AP33772::new(i2c.clone());
+    assert!(!pdc.poll(&mut NoopDelay, 100).unwrap().success());
+    assert!(pdc.poll(&mut NoopDelay, 100).unwrap().success());
+    i2c.done();
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn wait_event() {
+    use core::future::Future;
+    use core::pin::pin;
+    use core::task::{Context, Poll, Waker};
+    use embedded_hal_mock::eh1::digital::{Mock as PinMock, State, Transaction as PinTransaction};
+
+    let mut irq = PinMock::new(&[PinTransaction::wait_for_state(State::High)]);
+    let mut i2c = Mock::new(&[status(0x01)]);
+    let mut pdc = AP33772::new(i2c.clone()).with_irq_pin(irq.clone());
+    let mut fut = pin!(pdc.wait_event());
+    let Poll::Ready(event) = fut.as_mut().poll(&mut Context::from_waker(Waker::noop())) else {
+        panic!("mock pin is high immediately");
+    };
+    assert_eq!(event.unwrap(), PdEvent::Ready);
+    i2c.done();
+    irq.done();
+}
+
+#[test]
+fn wait_ready() {
+    let mut i2c = Mock::new(&[status(0x00), status(0x00), status(0x01)]);
+    let mut pdc = AP33772::new(i2c.clone());
+    assert!(pdc.wait_ready(&mut NoopDelay, 100).unwrap());
+    i2c.done();
+}
+
+#[test]
+fn tick_reissues_request() {
+    let [npdos, pdos] = pdos(&[0x0001_912c]);
+    let mut i2c = Mock::new(&[
+        status(0x05),
+        npdos,
+        pdos,
+        rdo(0x1004_b12c),
+        status(0x01),
+        rdo(0x1004_b12c),
+        status(0x03),
+        // success momentarily missing on an established contract
+        status(0x01),
+    ]);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.update().unwrap();
+    pdc.request_fixed(0, 3000).unwrap();
+    pdc.update().unwrap();
+    assert!(!pdc.tick(0).unwrap());
+    assert!(!pdc.tick(500).unwrap());
+    assert!(pdc.tick(1000).unwrap());
+    pdc.update().unwrap();
+    assert!(!pdc.tick(2500).unwrap());
+    pdc.update().unwrap();
+    assert!(!pdc.tick(3000).unwrap());
+    assert!(!pdc.tick(5000).unwrap());
+    i2c.done();
+}
+
+#[test]
+fn cable_current_limit() {
+    // 5 V / 3 A fixed
+    let [npdos, pdos] = pdos(&[0x0001_912c]);
+    let mut i2c = Mock::new(&[status(0x05), npdos, pdos, rdo(0x1003_20c8)]);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.update().unwrap();
+    pdc.set_cable_current_limit(Some(2000));
+    assert_eq!(pdc.can_supply(5000, 2500), None);
+    pdc.request_fixed(0, 3000).unwrap();
+    i2c.done();
+}
+
+#[test]
+fn max_power() {
+    // 5 V / 3 A fixed, 20 V / 5 A fixed
+    let [npdos, pdos] = pdos(&[0x0001_912c, 0x0006_41f4]);
+    let mut i2c = Mock::new(&[status(0x05), npdos, pdos, rdo(0x2002_5896)]);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.update().unwrap();
+    pdc.set_max_power(Some(30_000));
+    let req = PowerRequest {
+        v_nom: 20000,
+        v_min: 20000,
+        v_max: 20000,
+        i_nom: 5000,
+        i_min: 1000,
+    };
+    // 1.5 A at 20 V
+    assert_eq!(
+        pdc.negotiate(&req, SelectionPolicy::default())
+            .unwrap()
+            .get(),
+        2
+    );
+    assert_eq!(pdc.can_supply(20000, 2000), None);
+    i2c.done();
+}
+
+#[test]
+fn max_power_large_budget() {
+    // 20 V / 5 A fixed
+    let [npdos, pdos] = pdos(&[0x0006_41f4]);
+    let mut i2c = Mock::new(&[status(0x05), npdos, pdos]);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.update().unwrap();
+    // mW times 1000 does not fit a u32
+    pdc.set_max_power(Some(u32::MAX));
+    assert_eq!(pdc.can_supply(20000, 5000), Some(0));
+    pdc.set_max_power(Some(5_000_000));
+    assert_eq!(pdc.can_supply(20000, 5000), Some(0));
+    i2c.done();
+}
+
+/// Records the requested delays instead of waiting.
+#[derive(Default)]
+struct RecordingDelay(Vec<u32>);
+
+impl embedded_hal::delay::DelayNs for RecordingDelay {
+    fn delay_ns(&mut self, ns: u32) {
+        self.0.push(ns / 1_000_000);
+    }
+
+    fn delay_ms(&mut self, ms: u32) {
+        self.0.push(ms);
+    }
+}
+
+#[test]
+fn retry() {
+    use embedded_hal::i2c::ErrorKind;
+
+    let nak = || status(0x01).with_error(ErrorKind::Other);
+    let mut i2c = Mock::new(&[nak(), nak(), status(0x01), nak(), nak(), nak(), nak()]);
+    let mut pdc = AP33772::new(i2c.clone());
+    let mut delay = RecordingDelay::default();
+    assert_eq!(pdc.update_retry(&mut delay).unwrap(), PdEvent::Ready);
+    assert_eq!(delay.0, [1, 2]);
+    // 3 retries by default, the error of the last attempt is returned
+    let mut delay = RecordingDelay::default();
+    assert_eq!(pdc.update_retry(&mut delay).unwrap_err(), ErrorKind::Other);
+    assert_eq!(delay.0, [1, 2, 4]);
+    i2c.done();
+}
+
+#[test]
+fn retry_backoff_limit() {
+    use embedded_hal::i2c::ErrorKind;
+
+    let nak = rdo(0x1004_b12c).with_error(ErrorKind::Other);
+    let mut i2c = Mock::new(&vec![nak; 41]);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.set_retries(40);
+    let mut delay = RecordingDelay::default();
+    let rdo = RDO::FixedRDO(FixedRDO(0x1004_b12c));
+    assert!(pdc.write_rdo_retry(&rdo, &mut delay).is_err());
+    assert_eq!(delay.0.len(), 40);
+    assert_eq!(delay.0[..11], [1, 2, 4, 8, 16, 32, 64, 128, 256, 512, 1000]);
+    assert!(delay.0[11..].iter().all(|&ms| ms == 1000));
+    i2c.done();
+}
+
+#[test]
+fn probe() {
+    let mut i2c = Mock::new(&[
+        Transaction::write_read(ADDR, vec![0x1c], vec![2]),
+        Transaction::write_read(ADDR, vec![0x1c], vec![0xff]),
+    ]);
+    let mut pdc = AP33772::new(i2c.clone());
+    assert!(pdc.probe().is_ok());
+    assert!(matches!(pdc.probe(), Err(Error::UnexpectedDevice)));
+    i2c.done();
+}
+
+#[test]
+fn short_pdo_read() {
+    let [npdos, pdos_ok] = pdos(&[0x0001_912c, 0xc0dc_213c]);
+    let [_, pdos_short] = pdos(&[0x0001_912c, 0]);
+    let mut i2c = Mock::new(&[
+        status(0x05),
+        npdos.clone(),
+        pdos_short,
+        status(0x01),
+        npdos,
+        pdos_ok,
+    ]);
+    let mut pdc = AP33772::new(i2c.clone());
+    // the truncated read is not taken as "no PDOs" but repeated
+    pdc.update().unwrap();
+    assert_eq!(pdc.pdo_count(), 0);
+    pdc.update().unwrap();
+    assert_eq!(pdc.pdo_count(), 2);
+    i2c.done();
+}
+
+#[test]
+fn dump_registers() {
+    let reg = |reg: u8, val: &[u8]| Transaction::write_read(ADDR, vec![reg], val.to_vec());
+    let pdos = 0x0001_912c_u32.to_le_bytes();
+    let tr = [0x10, 0x27, 0x0f, 0x0f, 0xa4, 0x06, 0x29, 0x03];
+    let mut transactions = tr_writes(tr);
+    transactions.extend([
+        status(0x03),
+        reg(0x00, &[&pdos[..], &[0; 24]].concat()),
+        reg(0x1c, &[1]),
+        reg(0x1e, &[0xf7]),
+        reg(0x20, &[62]),
+        reg(0x21, &[10]),
+        reg(0x22, &[25]),
+        reg(0x23, &[2]),
+        reg(0x24, &[20]),
+        reg(0x25, &[80]),
+        reg(0x28, &[0x10, 0x27]),
+        reg(0x2a, &[0x0f, 0x0f]),
+        reg(0x2c, &[0xa4, 0x06]),
+        reg(0x2e, &[0x29, 0x03]),
+    ]);
+    let mut i2c = Mock::new(&transactions);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.write_tr(tr).unwrap();
+    let dump = pdc.dump_registers().unwrap();
+    assert_eq!(dump.pdos[..4], pdos);
+    assert_eq!((dump.npdos, dump.status, dump.irqmask), (1, 0x03, 0xf7));
+    assert_eq!((dump.voltage, dump.current, dump.temp), (62, 10, Some(25)));
+    assert_eq!((dump.ocpthr, dump.otpthr, dump.drthr), (2, 20, 80));
+    assert_eq!(dump.tr, tr);
+    i2c.done();
+}
+
+#[test]
+fn output_debounce() {
+    use embedded_hal_mock::eh1::digital::{Mock as PinMock, State, Transaction as PinTransaction};
+
+    let mut i2c = Mock::new(&[
+        rdo(0x1004_b12c),
+        status(0x03),
+        status(0x23),
+        status(0x03),
+        status(0x03),
+    ]);
+    let mut pin = PinMock::new(&[
+        PinTransaction::set(State::High),
+        PinTransaction::set(State::Low),
+        PinTransaction::set(State::High),
+    ]);
+    let mut pdc = AP33772::new(i2c.clone()).with_enable_pin(pin.clone());
+    pdc.set_output_debounce(2);
+    pdc.write_rdo(&RDO::FixedRDO(FixedRDO(0x1004_b12c)))
+        .unwrap();
+    pdc.update().unwrap();
+    assert!(pdc.output_enabled());
+    // OCP, then re-enabled on the second read without fault
+    pdc.update().unwrap();
+    pdc.update().unwrap();
+    assert!(!pdc.output_enabled());
+    pdc.update().unwrap();
+    assert!(pdc.output_enabled());
+    drop(pdc);
+    i2c.done();
+    pin.done();
+}
+
+#[test]
+fn output_requires_own_contract() {
+    use embedded_hal_mock::eh1::digital::{Mock as PinMock, State, Transaction as PinTransaction};
+
+    let mut i2c = Mock::new(&[status(0x03), rdo(0x1004_b12c), status(0x03)]);
+    let mut pin = PinMock::new(&[PinTransaction::set(State::High)]);
+    let mut pdc = AP33772::new(i2c.clone()).with_enable_pin(pin.clone());
+    // success left over from before start-up
+    pdc.update().unwrap();
+    assert!(!pdc.output_enabled());
+    pdc.write_rdo(&RDO::FixedRDO(FixedRDO(0x1004_b12c)))
+        .unwrap();
+    pdc.update().unwrap();
+    assert!(pdc.output_enabled());
+    drop(pdc);
+    i2c.done();
+    pin.done();
+}
+
+#[test]
+fn hard_reset_keeps_output_off() {
+    use embedded_hal_mock::eh1::digital::{Mock as PinMock, Transaction as PinTransaction};
+
+    let mut i2c = Mock::new(&[
+        rdo(0),
+        // the link drop is missed, back at 5 V
+        status(0x03),
+    ]);
+    let mut pin = PinMock::new(&[] as &[PinTransaction]);
+    let mut pdc = AP33772::new(i2c.clone()).with_enable_pin(pin.clone());
+    pdc.hard_reset().unwrap();
+    assert_eq!(pdc.update().unwrap(), PdEvent::Negotiated);
+    assert!(!pdc.output_enabled());
+    drop(pdc);
+    i2c.done();
+    pin.done();
+}
+
+#[test]
+fn source_reset() {
+    // 5 V / 3 A fixed
+    let [npdos, pdos] = pdos(&[0x0001_912c]);
+    let mut i2c = Mock::new(&[
+        status(0x05),
+        npdos.clone(),
+        pdos.clone(),
+        rdo(0x1004_b12c),
+        status(0x00),
+        // back without the new PDO flag
+        status(0x01),
+        npdos,
+        pdos,
+    ]);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.update().unwrap();
+    pdc.request_fixed(0, 3000).unwrap();
+    assert_eq!(pdc.update().unwrap(), PdEvent::SourceReset);
+    assert_eq!(pdc.pdo_count(), 0);
+    pdc.update().unwrap();
+    assert_eq!(pdc.pdo_count(), 1);
+    i2c.done();
+}
+
+#[test]
+fn trace() {
+    use std::sync::Mutex;
+
+    static TRACE: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    let mut i2c = Mock::new(&[
+        Transaction::write_read(ADDR, vec![0x20], vec![62]),
+        Transaction::write(ADDR, vec![0x1e, 0xf7]),
+    ]);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.set_trace(Some(|t| TRACE.lock().unwrap().push(format!("{t:x?}"))));
+    pdc.read_voltage().unwrap();
+    pdc.write_irqmask(0xf7).unwrap();
+    assert_eq!(
+        *TRACE.lock().unwrap(),
+        [
+            "Read { reg: 20, data: [3e] }",
+            "Write { reg: 1e, data: [f7] }"
+        ]
+    );
+    i2c.done();
+}
+
+#[test]
+fn contract_headroom() {
+    // 5 V / 3 A fixed
+    let [npdos, pdos] = pdos(&[0x0001_912c]);
+    let mut i2c = Mock::new(&[
+        status(0x05),
+        npdos,
+        pdos,
+        rdo(0x1003_20c8),
+        status(0x03),
+        Transaction::write_read(ADDR, vec![0x21], vec![62]),
+        Transaction::write_read(ADDR, vec![0x21], vec![100]),
+    ]);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.update().unwrap();
+    assert_eq!(pdc.contract_headroom_ma().unwrap(), None);
+    pdc.request_fixed(0, 2000).unwrap();
+    pdc.update().unwrap();
+    assert_eq!(pdc.contract_headroom_ma().unwrap(), Some(512));
+    assert_eq!(pdc.contract_headroom_ma().unwrap(), Some(-400));
+    i2c.done();
+}
+
+#[test]
+fn verify_output() {
+    // 9 V / 3 A fixed
+    let [npdos, pdos] = pdos(&[0x0002_d12c]);
+    let mut i2c = Mock::new(&[
+        status(0x05),
+        npdos,
+        pdos,
+        rdo(0x1003_20c8),
+        status(0x03),
+        Transaction::write_read(ADDR, vec![0x20], vec![112]),
+        Transaction::write_read(ADDR, vec![0x20], vec![62]),
+    ]);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.update().unwrap();
+    assert!(!pdc.verify_output(500).unwrap());
+    pdc.request_fixed(0, 2000).unwrap();
+    pdc.update().unwrap();
+    assert!(pdc.verify_output(500).unwrap());
+    assert!(!pdc.verify_output(500).unwrap());
+    i2c.done();
+}
+
+#[test]
+fn request_remembered() {
+    // 5 V / 3 A fixed, 3.3 - 11 V / 3 A programmable
+    let [npdos, pdos] = pdos(&[0x0001_912c, 0xc0dc_213c]);
+    let mut i2c = Mock::new(&[
+        status(0x05),
+        npdos,
+        pdos,
+        rdo(0x2003_b628),
+        status(0x03),
+        rdo(0x2003_b628),
+    ]);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.update().unwrap();
+    assert_eq!(pdc.saved_contract(), None);
+    pdc.request_pps(1, 9500, 2000).unwrap();
+    pdc.update().unwrap();
+    let saved = pdc.saved_contract().unwrap();
+    assert_eq!(
+        saved,
+        SavedContract {
+            voltage_mv: 9500,
+            current_ma: 2000,
+            kind: PdoKind::Programmable,
+        }
+    );
+    assert_eq!(SavedContract::from_bytes(saved.to_bytes()), Some(saved));
+    assert_eq!(SavedContract::from_bytes([0xff; 5]), None);
+
+    assert!(pdc.request_remembered(saved).unwrap());
+    // no fixed 9.5 V PDO, nor 4 A
+    let fixed = SavedContract {
+        kind: PdoKind::Fixed,
+        ..saved
+    };
+    assert!(!pdc.request_remembered(fixed).unwrap());
+    let more = SavedContract {
+        current_ma: 4000,
+        ..saved
+    };
+    assert!(!pdc.request_remembered(more).unwrap());
+    i2c.done();
+}
+
+#[test]
+fn init() {
+    use embedded_hal::i2c::ErrorKind;
+
+    let cfg = InitConfig {
+        tr: [0x10, 0x27, 0x0f, 0x0f, 0xa4, 0x06, 0x29, 0x03],
+        config: DeviceConfig {
+            irq_mask: IrqMask::all(),
+            ocp_ma: 100,
+            otp_c: 20,
+            dr_c: 80,
+        },
+    };
+    let npdos = Transaction::write_read(ADDR, vec![0x1c], vec![1]);
+    let [_, pdos] = pdos(&[0x0001_912c]);
+    let mut expectations = vec![npdos.clone()];
+    expectations.extend(tr_writes(cfg.tr));
+    expectations.extend([
+        Transaction::write(ADDR, vec![0x23, 2]),
+        Transaction::write(ADDR, vec![0x24, 20]),
+        Transaction::write(ADDR, vec![0x25, 80]),
+        Transaction::write(ADDR, vec![0x1e, 0xf7]),
+        // the status of a fresh driver is read, not taken from the cache
+        status(0x01),
+        npdos.clone(),
+        pdos,
+        // I2C error on the first threshold write
+        npdos,
+    ]);
+    expectations.extend(tr_writes(cfg.tr));
+    expectations.push(Transaction::write(ADDR, vec![0x23, 2]).with_error(ErrorKind::Other));
+    let mut i2c = Mock::new(&expectations);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.init(&cfg).unwrap();
+    assert_eq!(pdc.pdo_count(), 1);
+    assert!(matches!(pdc.init(&cfg), Err(Error::I2c(ErrorKind::Other))));
+    i2c.done();
+}
+
+#[test]
+fn select_prefers_programmable() {
+    // 9 V / 3 A fixed, 3.3 - 11 V / 3 A programmable
+    let [npdos, pdos] = pdos(&[0x0002_d12c, 0xc0dc_213c]);
+    let mut i2c = Mock::new(&[status(0x05), npdos, pdos]);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.update().unwrap();
+    let req = PowerRequest {
+        v_nom: 9000,
+        v_min: 9000,
+        v_max: 9000,
+        i_nom: 2000,
+        i_min: 2000,
+    };
+    assert_eq!(
+        pdc.select_pdo(&req, SelectionPolicy::PreferProgrammable),
+        Some(1)
+    );
+    i2c.done();
+}
+
+#[test]
+fn pdos_by_type() {
+    // 5 V / 3 A fixed, 3.3 - 11 V / 3 A programmable, 9 V / 3 A fixed
+    let [npdos, pdos] = pdos(&[0x0001_912c, 0xc0dc_213c, 0x0002_d12c]);
+    let mut i2c = Mock::new(&[status(0x05), npdos, pdos]);
+    let mut pdc = AP33772::new(i2c.clone());
+    assert!(!pdc.supports_pps());
+    pdc.update().unwrap();
+    let fixed: Vec<_> = pdc.fixed_pdos().map(|(pos, pdo)| (pos, pdo.v())).collect();
+    assert_eq!(fixed, [(1, 100), (3, 180)]);
+    let pps: Vec<_> = pdc.pps_pdos().map(|(pos, pdo)| (pos, pdo.vmax())).collect();
+    assert_eq!(pps, [(2, 110)]);
+    assert!(pdc.supports_pps());
+    i2c.done();
+}
+
+#[test]
+fn select_without_pps() {
+    // 9 V / 3 A fixed, 3.3 - 11 V / 3 A programmable
+    let [npdos, pdos] = pdos(&[0x0002_d12c, 0xc0dc_213c]);
+    let mut i2c = Mock::new(&[status(0x05), npdos, pdos]);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.update().unwrap();
+    pdc.set_allow_pps(false);
+    let mut req = PowerRequest {
+        v_nom: 9000,
+        v_min: 9000,
+        v_max: 9000,
+        i_nom: 2000,
+        i_min: 2000,
+    };
+    assert_eq!(
+        pdc.select_pdo(&req, SelectionPolicy::PreferProgrammable),
+        Some(0)
+    );
+    req.v_nom = 7000;
+    req.v_min = 7000;
+    req.v_max = 7000;
+    assert_eq!(pdc.select_pdo(&req, SelectionPolicy::default()), None);
+    assert!(matches!(
+        pdc.request_voltage(7000, 1000),
+        Err(Error::NoCompatiblePdo)
+    ));
+    i2c.done();
+}
+
+#[test]
+fn select_max_current() {
+    // 9 V / 3 A fixed, 3.3 - 11 V / 2 A programmable
+    let [npdos, pdos] = pdos(&[0x0002_d12c, 0xc0dc_2128]);
+    let mut i2c = Mock::new(&[status(0x05), npdos, pdos]);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.update().unwrap();
+    let req = PowerRequest {
+        v_nom: 9000,
+        v_min: 9000,
+        v_max: 9000,
+        i_nom: 2000,
+        i_min: 2000,
+    };
+    assert_eq!(pdc.select_pdo(&req, SelectionPolicy::MaxCurrent), Some(0));
+    assert_eq!(
+        pdc.select_pdo(&req, SelectionPolicy::PreferProgrammable),
+        Some(1)
+    );
+    i2c.done();
+}
+
+#[test]
+fn adjust_current() {
+    // 5 V / 3 A fixed, 3.3 - 11 V / 3 A programmable
+    let [npdos, pdos] = pdos(&[0x0001_912c, 0xc0dc_213c]);
+    let mut i2c = Mock::new(&[
+        status(0x05),
+        npdos,
+        pdos,
+        rdo(0x2003_8428),
+        rdo(0x2003_841e),
+        rdo(0x1003_20c8),
+        rdo(0x1404_b12c),
+    ]);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.update().unwrap();
+    assert!(matches!(
+        pdc.adjust_current(1000),
+        Err(Error::InvalidPdoIndex)
+    ));
+    // 9 V at 2 A, then 1.5 A
+    pdc.request_pps(1, 9000, 2000).unwrap();
+    pdc.adjust_current(1500).unwrap();
+    // 5 V at 2 A, then clamped to 3 A with capability mismatch
+    pdc.request_fixed(0, 2000).unwrap();
+    pdc.adjust_current(4000).unwrap();
+    i2c.done();
+}
+
+#[test]
+fn ensure_safe_default() {
+    // 5 V / 3 A fixed, 9 V / 3 A fixed
+    let [npdos, pdos] = pdos(&[0x0001_912c, 0x0002_d12c]);
+    let mut i2c = Mock::new(&[status(0x05), npdos, pdos, rdo(0x1004_b12c)]);
+    let mut pdc = AP33772::new(i2c.clone());
+    assert!(matches!(
+        pdc.ensure_safe_default(),
+        Err(Error::NoCompatiblePdo)
+    ));
+    pdc.update().unwrap();
+    pdc.ensure_safe_default().unwrap();
+    i2c.done();
+}
+
+#[test]
+fn fault_counters() {
+    let mut i2c = Mock::new(&[
+        status(0x23),
+        status(0x23),
+        status(0x03),
+        status(0xa3),
+        status(0x53),
+    ]);
+    let mut pdc = AP33772::new(i2c.clone());
+    for _ in 0..5 {
+        pdc.update().unwrap();
+    }
+    assert_eq!(
+        pdc.fault_counters(),
+        FaultCounters {
+            ovp: 1,
+            ocp: 2,
+            otp: 1,
+            derating: 1,
+        }
+    );
+    i2c.done();
+}
+
+#[test]
+fn write_tr_order() {
+    let tr = [0x10, 0x27, 0x41, 0x10, 0x88, 0x07, 0xce, 0x03];
+    let mut i2c = Mock::new(&tr_writes(tr));
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.write_tr(tr).unwrap();
+    // TR50 and TR75 swapped
+    let swapped = [0x10, 0x27, 0x88, 0x07, 0x41, 0x10, 0xce, 0x03];
+    assert!(matches!(pdc.write_tr(swapped), Err(Error::ValueOutOfRange)));
+    assert!(matches!(pdc.write_tr([0; 8]), Err(Error::ValueOutOfRange)));
+    i2c.done();
+}
+
+#[test]
+fn write_ocpthr_range() {
+    let mut i2c = Mock::new(&[Transaction::write(ADDR, vec![0x23, 255])]);
+    let mut pdc = AP33772::new(i2c.clone());
+    pdc.write_ocpthr(12750).unwrap();
+    assert!(matches!(
+        pdc.write_ocpthr(12800),
+        Err(Error::ValueOutOfRange)
+    ));
+    i2c.done();
+}